@@ -1,14 +1,17 @@
 use anyhow::Result;
-use image::{buffer::ConvertBuffer, ImageBuffer, ImageReader, Rgb};
+use image::{buffer::ConvertBuffer, ImageBuffer, ImageReader, Rgb, Rgba};
 use notify::Watcher;
 use rouille::Response;
 use std::{
     borrow::Cow,
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     ffi::OsStr,
     fs::File as FsFile,
     path::{Path, PathBuf},
-    sync::RwLock,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
 };
 
 mod path;
@@ -32,6 +35,70 @@ fn percent_encode(s: &str) -> String {
     encoded.to_string()
 }
 
+fn html_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&#39;",
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Whether a link is http(s) or relative, and not something like `javascript:`
+fn is_safe_link(url: &str) -> bool {
+    // browsers skip whitespace and control characters when reading the scheme
+    let url = url
+        .chars()
+        .filter(|c| !c.is_ascii_whitespace() && !c.is_ascii_control())
+        .collect::<String>();
+    match url.find([':', '/', '?', '#']) {
+        Some(i) if url[i..].starts_with(':') => {
+            let scheme = &url[..i];
+            scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https")
+        }
+        _ => true,
+    }
+}
+
+fn markdown_to_html(markdown: &str) -> String {
+    use pulldown_cmark::{Event, Parser, Tag};
+    let parser = Parser::new(markdown).map(|event| match event {
+        Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+        Event::Start(Tag::Link {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) if !is_safe_link(&dest_url) => Event::Start(Tag::Link {
+            link_type,
+            dest_url: "#".into(),
+            title,
+            id,
+        }),
+        Event::Start(Tag::Image {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) if !is_safe_link(&dest_url) => Event::Start(Tag::Image {
+            link_type,
+            dest_url: "#".into(),
+            title,
+            id,
+        }),
+        event => event,
+    });
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, parser);
+    html
+}
+
 #[derive(Default)]
 struct Page {
     tab_title: String,
@@ -76,18 +143,32 @@ impl Page {
         }
     }
 
-    fn render(self, _config: &Config) -> Response {
+    fn render(self, config: &Config) -> Response {
         let title = if let Some(code) = self.code {
             format!("{}: {}", code, self.title)
         } else {
             self.title
         };
 
+        let site_name = html_escape(&config.page_title);
+        let tab_title = if self.tab_title.is_empty() {
+            site_name
+        } else {
+            format!("{} - {}", self.tab_title, site_name)
+        };
+
+        let favicon = if config.favicon_path.is_some() {
+            format!("<link rel=\"icon\" href=\"{}?favicon\">", config.root_url())
+        } else {
+            String::new()
+        };
+
         Response::html(format!(
             r#"<!DOCTYPE html>
 <html>
   <head>
     <title>{}</title>
+    {}
     <style>
       h1 {{ color: green; }}
       .icon > img {{
@@ -132,6 +213,19 @@ impl Page {
       #searchbox {{
         flex-grow: 1;
       }}
+      .row.hidden > div {{
+        opacity: 0.6;
+      }}
+      .readme {{
+        padding-top: 1em;
+      }}
+      .readme pre {{
+        white-space: pre-wrap;
+      }}
+      .counts {{
+        opacity: 0.6;
+        font-size: smaller;
+      }}
     </style>
     <meta name="viewport" content="width=device-width, initial-scale=1, maximum-scale=1, minimum-scale=1, minimal-ui">
   </head>
@@ -142,15 +236,21 @@ impl Page {
   </body>
 </html>
 "#,
-            self.tab_title, title, self.content
+            tab_title, favicon, title, self.content
         ))
         .with_status_code(self.code.unwrap_or(200))
     }
 
-    fn not_found(config: &Config) -> Response {
+    fn not_found(config: &Config, request: &rouille::Request) -> Response {
         Self::default()
+            .with_tab_title("404 not found")
             .with_title("not found")
-            .with_paragraph("skill issue")
+            .with_content(format!(
+                "<p><code>{}</code></p><p>{}</p><p><a href='{}'>go home</a></p>",
+                html_escape(request.raw_url()),
+                html_escape(config.not_found_message.as_deref().unwrap_or("skill issue")),
+                config.root_url(),
+            ))
             .with_code(404)
             .render(config)
     }
@@ -163,6 +263,22 @@ impl Page {
             .render(config)
     }
 
+    fn unauthorized(config: &Config) -> Response {
+        Self::default()
+            .with_title("unauthorized")
+            .with_paragraph("skill issue")
+            .with_code(401)
+            .render(config)
+    }
+
+    fn forbidden(config: &Config) -> Response {
+        Self::default()
+            .with_title("forbidden")
+            .with_paragraph("skill issue")
+            .with_code(403)
+            .render(config)
+    }
+
     fn internal_error(config: &Config) -> Response {
         Self::default()
             .with_title("internal server error")
@@ -172,18 +288,66 @@ impl Page {
     }
 }
 
-fn thumbnail_path(of: &Path, thumbnail_dir: &LocalPath) -> ThumbnailPath {
+fn hex_digest(hasher: md5_rs::Context) -> String {
+    hasher
+        .finish()
+        .into_iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>()
+}
+
+/// Doesn't stop at the first difference, so the time taken doesn't tell how much of a secret matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn path_hash(of: &Path) -> String {
     let name = format!("{}", of.display());
 
     let mut hasher = md5_rs::Context::new();
     hasher.read(name.as_bytes());
-    let hash = hasher
-        .finish()
-        .into_iter()
-        .map(|byte| format!("{:02x}", byte))
-        .collect::<String>();
+    hex_digest(hasher)
+}
+
+/// Size, modification time and (on unix) inode all survive a rename, and only need a stat.
+/// Elsewhere the first 64KB stand in for the inode
+fn metadata_hash(of: &Path) -> Result<String> {
+    let meta = std::fs::metadata(of)
+        .map_err(|e| af!("couldn't get metadata of {}: {}", of.display(), e))?;
+    let modified = meta
+        .modified()
+        .map_err(|e| af!("couldn't get modified time of {}: {}", of.display(), e))?
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
 
-    ThumbnailPath::from(thumbnail_dir.local_path().join(hash).with_extension("jpg"))
+    let mut hasher = md5_rs::Context::new();
+    hasher.read(&meta.len().to_le_bytes());
+    hasher.read(&modified.as_nanos().to_le_bytes());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        hasher.read(&meta.dev().to_le_bytes());
+        hasher.read(&meta.ino().to_le_bytes());
+    }
+    #[cfg(not(unix))]
+    {
+        use std::io::Read;
+        let mut start = Vec::new();
+        FsFile::open(of)
+            .and_then(|file| file.take(64 * 1024).read_to_end(&mut start))
+            .map_err(|e| af!("couldn't read {}: {}", of.display(), e))?;
+        hasher.read(&start);
+    }
+    Ok(hex_digest(hasher))
+}
+
+fn thumbnail_path(hash: &str, size: u32, thumbnail_dir: &LocalPath) -> ThumbnailPath {
+    ThumbnailPath::from(
+        thumbnail_dir
+            .local_path()
+            .join(format!("{}_{}", hash, size))
+            .with_extension("jpg"),
+    )
 }
 
 #[derive(Debug)]
@@ -195,8 +359,30 @@ enum File {
 impl File {
     const THUMBNAILABLE_EXTENSIONS: &'static [&'static str] =
         &["png", "tiff", "bmp", "gif", "jpeg", "jpg", "tif"];
+    /// Images the image crate can't decode with the features enabled here
+    const UNSUPPORTED_IMAGE_EXTENSIONS: &'static [&'static str] = &["avif", "heic", "heif"];
+
+    const MAX_WALK_DEPTH: u32 = 500;
+
+    fn walk_dir(
+        dir: &LocalPath,
+        depth: u32,
+        max_depth: Option<u32>,
+        follow_symlinks: bool,
+        visited: &mut HashSet<PathBuf>,
+        scanned: &mut usize,
+        include_path: &impl Fn(&Path) -> bool,
+    ) -> Result<Vec<File>> {
+        if depth > max_depth.unwrap_or(u32::MAX) || depth > Self::MAX_WALK_DEPTH {
+            tracing::warn!(
+                "not walking {}, too deep ({} levels)",
+                dir.local_path().display(),
+                depth
+            );
+            return Ok(Vec::new());
+        }
+        visited.insert(dir.local_path().to_path_buf());
 
-    fn walk_dir(dir: &LocalPath, include_path: &impl Fn(&Path) -> bool) -> Result<Vec<File>> {
         let mut contents = Vec::new();
         for entry in dir
             .local_path()
@@ -210,23 +396,70 @@ impl File {
                     e
                 )
             })?;
-            let path = entry.path().canonicalize().map_err(|e| {
-                af!(
-                    "couldn't get absolute path of {}: {}",
+
+            let is_symlink = entry
+                .file_type()
+                .map(|file_type| file_type.is_symlink())
+                .unwrap_or(false);
+            if is_symlink && !follow_symlinks {
+                tracing::warn!("skipping symlink {}", entry.path().display());
+                continue;
+            }
+
+            let path = match entry.path().canonicalize() {
+                Ok(path) => path,
+                Err(e) if is_symlink => {
+                    tracing::warn!("skipping broken symlink {}: {}", entry.path().display(), e);
+                    continue;
+                }
+                Err(e) => {
+                    return Err(af!(
+                        "couldn't get absolute path of {}: {}",
+                        entry.path().display(),
+                        e
+                    ))
+                }
+            };
+
+            if !include_path(&path) {
+                continue;
+            }
+
+            // links can lead back to a dir we're inside of, or to one that was
+            // already walked through another link, so each dir is only walked once
+            if path.is_dir() && !visited.insert(path.clone()) {
+                tracing::warn!(
+                    "skipping {}, {} was already indexed",
                     entry.path().display(),
-                    e
-                )
-            })?;
+                    path.display()
+                );
+                continue;
+            }
 
-            if include_path(&path) {
-                contents.push(if path.is_dir() {
-                    let local_path = LocalPath::from(path);
-                    let inner = Self::walk_dir(&local_path, include_path)?;
-                    File::Dir(local_path, inner)
-                } else {
-                    File::File(LocalPath::from(path))
-                });
+            *scanned += 1;
+            if scanned.is_multiple_of(1000) {
+                tracing::info!(
+                    "scanned {} files so far, now in {}",
+                    scanned,
+                    dir.local_path().display()
+                );
             }
+
+            contents.push(if path.is_dir() {
+                let local_path = LocalPath::from(path);
+                let inner = Self::walk_dir(
+                    &local_path,
+                    depth + 1,
+                    max_depth,
+                    follow_symlinks,
+                    visited,
+                    scanned,
+                    include_path,
+                )?;
+                File::Dir(local_path, inner)
+            } else {
+                File::File(LocalPath::from(path))
+            });
         }
 
         Ok(contents)
@@ -276,20 +509,126 @@ impl File {
             File::File(local_path) => local_path,
         }
     }
+
+    fn count(files: &[File]) -> (usize, usize) {
+        files.iter().fold((0, 0), |(files, dirs), file| match file {
+            File::Dir(_, inner) => {
+                let (inner_files, inner_dirs) = Self::count(inner);
+                (files + inner_files, dirs + inner_dirs + 1)
+            }
+            File::File(_) => (files + 1, dirs),
+        })
+    }
+
+    fn export(&self, db: &Database) -> IndexEntry {
+        let local_path = self.local_path().local_path();
+        let meta = local_path.metadata();
+
+        let child_items = match self {
+            File::Dir(_, files) => files.iter().map(|file| file.export(db)).collect(),
+            File::File(_) => Vec::new(),
+        };
+        // every file and dir below this one, not just the direct children
+        let items_in_subdirs = child_items.len()
+            + child_items
+                .iter()
+                .map(|child: &IndexEntry| child.items_in_subdirs)
+                .sum::<usize>();
+        let (dir_count, file_count) = match self {
+            File::Dir(..) => {
+                let dirs = child_items.iter().filter(|child| child.is_dir).count();
+                (Some(dirs), Some(child_items.len() - dirs))
+            }
+            File::File(_) => (None, None),
+        };
+
+        IndexEntry {
+            part_name: local_path
+                .strip_prefix(db.file_dir.local_path())
+                .unwrap_or(local_path)
+                .display()
+                .to_string(),
+            full_path: local_path.display().to_string(),
+            is_dir: matches!(self, File::Dir(..)),
+            file_size: meta.as_ref().ok().map(|meta| meta.len()),
+            modified: meta
+                .and_then(|meta| meta.modified())
+                .ok()
+                .map(|modified| chrono::DateTime::<chrono::Local>::from(modified).to_rfc3339()),
+            thumbnails: db
+                .thumbnails
+                .get(self.local_path())
+                .map(|thumbnails| {
+                    thumbnails
+                        .iter()
+                        .flat_map(|(size, thumbnail)| {
+                            let name = thumbnail.thumbnail_path().file_name()?;
+                            Some((*size, name.to_string_lossy().to_string()))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            child_items,
+            items_in_subdirs,
+            dir_count,
+            file_count,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct IndexEntry {
+    part_name: String,
+    full_path: String,
+    is_dir: bool,
+    file_size: Option<u64>,
+    modified: Option<String>,
+    thumbnails: Vec<(u32, String)>,
+    child_items: Vec<IndexEntry>,
+    items_in_subdirs: usize,
+    dir_count: Option<usize>,
+    file_count: Option<usize>,
+}
+
+/// Lists the thumbnail dir once so checking for a thumbnail doesn't need a stat each
+fn existing_thumbnails(thumbnail_dir: &LocalPath) -> Result<HashSet<PathBuf>> {
+    thumbnail_dir
+        .local_path()
+        .read_dir()
+        .map_err(|e| {
+            af!(
+                "couldn't list thumbnail dir {}: {}",
+                thumbnail_dir.local_path().display(),
+                e
+            )
+        })?
+        .map(|entry| {
+            entry.map(|entry| entry.path()).map_err(|e| {
+                af!(
+                    "couldn't read entry in {}: {}",
+                    thumbnail_dir.local_path().display(),
+                    e
+                )
+            })
+        })
+        .collect()
 }
 
 fn build_thumbnail_db(
     files: &[File],
+    sizes: &[u32],
     thumbnail_dir: &LocalPath,
-) -> Result<HashMap<LocalPath, ThumbnailPath>> {
+) -> Result<HashMap<LocalPath, Vec<(u32, ThumbnailPath)>>> {
     fn btdb_rec(
-        db: &mut HashMap<LocalPath, ThumbnailPath>,
+        db: &mut HashMap<LocalPath, Vec<(u32, ThumbnailPath)>>,
+        hashed: &mut usize,
         files: &[File],
+        sizes: &[u32],
         thumbnail_dir: &LocalPath,
     ) -> Result<()> {
         for file in files {
             match file {
-                File::Dir(_, files) => btdb_rec(db, files, thumbnail_dir)?,
+                File::Dir(_, files) => btdb_rec(db, hashed, files, sizes, thumbnail_dir)?,
                 file @ File::File(path) if file.may_be_thumbnailed() => {
                     let path = path.local_path().canonicalize().map_err(|e| {
                         af!(
@@ -298,11 +637,41 @@ fn build_thumbnail_db(
                             e
                         )
                     })?;
-                    let thumbnail_path = thumbnail_path(&path, thumbnail_dir);
-                    db.insert(LocalPath::from(path), thumbnail_path);
+                    let Ok(hash) = metadata_hash(&path) else {
+                        tracing::warn!("couldn't hash {}, no thumbnail", path.display());
+                        continue;
+                    };
+                    *hashed += 1;
+                    if hashed.is_multiple_of(1000) {
+                        tracing::info!("hashed {} images so far", hashed);
+                    }
+
+                    let thumbnail_paths = sizes
+                        .iter()
+                        .map(|size| (*size, thumbnail_path(&hash, *size, thumbnail_dir)))
+                        .collect();
+                    db.insert(LocalPath::from(path), thumbnail_paths);
                 }
                 File::File(path) => {
-                    tracing::debug!("skipping thumbnail for {}", path.local_path().display());
+                    let extension = path
+                        .local_path()
+                        .extension()
+                        .map(|ext| ext.to_string_lossy().to_lowercase());
+                    match extension {
+                        Some(ext) if File::UNSUPPORTED_IMAGE_EXTENSIONS.contains(&ext.as_str()) => {
+                            tracing::warn!(
+                                "no thumbnail for {}, {} images aren't supported",
+                                path.local_path().display(),
+                                ext
+                            );
+                        }
+                        _ => {
+                            tracing::debug!(
+                                "skipping thumbnail for {}",
+                                path.local_path().display()
+                            );
+                        }
+                    }
                 }
             }
         }
@@ -311,7 +680,7 @@ fn build_thumbnail_db(
     }
 
     let mut db = HashMap::new();
-    btdb_rec(&mut db, files, thumbnail_dir)?;
+    btdb_rec(&mut db, &mut 0, files, sizes, thumbnail_dir)?;
     Ok(db)
 }
 
@@ -321,12 +690,21 @@ pub struct Database {
     #[allow(dead_code)]
     files: Vec<File>,
     thumbnail_dir: LocalPath,
-    thumbnails: HashMap<LocalPath, ThumbnailPath>,
+    thumbnails: HashMap<LocalPath, Vec<(u32, ThumbnailPath)>>,
     pages: RwLock<HashMap<LocalPath, String>>,
+    /// Cleared if the fs watcher thread stops, after which pages aren't cached
+    watcher_healthy: AtomicBool,
 }
 
 impl Database {
     fn open_thumbnail(&self, thumb: &str) -> Result<FsFile> {
+        let mut components = Path::new(thumb).components();
+        if !matches!(
+            (components.next(), components.next()),
+            (Some(std::path::Component::Normal(_)), None)
+        ) {
+            return Err(af!("thumbnail {} is not a file name", thumb));
+        }
         let thumbnail_path = self.thumbnail_dir.local_path().join(thumb);
         Ok(FsFile::open(thumbnail_path)?)
     }
@@ -357,10 +735,25 @@ impl Database {
         Ok(())
     }
 
+    fn find(&self, local_path: &LocalPath) -> Option<&File> {
+        self.files.iter().find_map(|file| file.find(local_path))
+    }
+
+    /// The root links straight to whatever trailing_slash would redirect it to
+    fn url_for(&self, config: &Config, path: &LocalPath) -> Result<String> {
+        if *path == self.file_dir {
+            Ok(config.root_url())
+        } else {
+            Ok(ServePath::from_local_path(self, config, path)?.to_string(true))
+        }
+    }
+
     fn get_content_for(&self, config: &Config, serve_dir: &ServePath) -> Result<Option<String>> {
         let local_dir = LocalPath::from_serve_path(&self, config, serve_dir)?;
 
-        {
+        // nothing would invalidate the cache without the watcher
+        let use_cache = self.watcher_healthy.load(Ordering::SeqCst);
+        if use_cache {
             let read = self
                 .pages
                 .read()
@@ -433,17 +826,60 @@ impl Database {
         page +=
             "<div class=\"header row\"><div></div><div>filename</div><div class=\"header created\">created</div><div class=\"header modified\">modified</div><div class=\"header accessed\">accessed</div></div>\n";
 
+        if let Some(parent) = local_dir
+            .local_path()
+            .parent()
+            .filter(|_| local_dir != self.file_dir)
+        {
+            let parent = LocalPath::from(parent.to_path_buf());
+            page += "<div class=\"dir row parent\">";
+            page += "<div class=\"dir icon\">📁</div>";
+            page += &format!(
+                "<div class=\"dir filename\"><a href='{}'>..</a></div>",
+                html_escape(&self.url_for(config, &parent)?)
+            );
+            page += "<div class=\"dir created\"></div>";
+            page += "<div class=\"dir modified\"></div>";
+            page += "<div class=\"dir accessed\"></div>";
+            page += "</div>\n";
+        }
+
         for (path, basename) in dirs.into_iter() {
-            page += "<div class=\"dir row\">";
+            page += if path.is_hidden() {
+                "<div class=\"dir row hidden\">"
+            } else {
+                "<div class=\"dir row\">"
+            };
 
             page += "<div class=\"dir icon\">📁</div>";
 
             page += "<div class=\"dir filename\">";
             page += &format!(
                 "<a href='{}'>{}</a>",
-                ServePath::from_local_path(&self, config, &path)?.to_string(true),
-                basename
+                html_escape(&ServePath::from_local_path(self, config, &path)?.to_string(true)),
+                html_escape(&basename)
             );
+            // counted from the same read_dir as the page, so they're as fresh as it is
+            if let Ok(entries) = path.local_path().read_dir() {
+                let (dirs, files) = entries
+                    .flatten()
+                    .filter(|entry| entry.path() != self.thumbnail_dir.local_path())
+                    .fold((0, 0), |(dirs, files), entry| {
+                        if entry.path().is_dir() {
+                            (dirs + 1, files)
+                        } else {
+                            (dirs, files + 1)
+                        }
+                    });
+                let plural = |n: usize, what: &str| {
+                    format!("{} {}{}", n, what, if n == 1 { "" } else { "s" })
+                };
+                page += &format!(
+                    " <span class=\"counts\">{}, {}</span>",
+                    plural(dirs, "folder"),
+                    plural(files, "file")
+                );
+            }
             page += "</div>";
 
             page += "<div class=\"dir created\">";
@@ -471,29 +907,73 @@ impl Database {
         }
 
         for (path, basename) in files.into_iter() {
-            page += "<div class=\"file row\">";
+            page += if path.is_hidden() {
+                "<div class=\"file row hidden\">"
+            } else {
+                "<div class=\"file row\">"
+            };
 
             page += "<div class=\"file icon\"";
-            if let Some(thumbnail_path) = self.thumbnails.get(&path) {
+            if let Some(thumbnail_paths) = self.thumbnails.get(&path) {
+                let thumbnail_url = |thumbnail_path: &ThumbnailPath| {
+                    format!(
+                        "{}?thumbnail={}",
+                        config.root_url(),
+                        thumbnail_path
+                            .thumbnail_path()
+                            .file_name()
+                            .map(OsStr::to_string_lossy)
+                            .unwrap_or_else(|| Cow::Borrowed("<broken filename>"))
+                    )
+                };
+                // heights aren't widths, so those are picked by pixel density
+                // relative to the smallest instead
+                let smallest = thumbnail_paths.first().map(|(size, _)| *size).unwrap_or(1);
+                let (descriptor, sizes): (fn(u32, u32) -> String, _) = match config.thumbnail_shape
+                {
+                    ThumbnailShape::Height => (
+                        |size, smallest| format!("{}x", size as f32 / smallest as f32),
+                        "",
+                    ),
+                    ThumbnailShape::Width | ThumbnailShape::Fixed { .. } => {
+                        (|size, _| format!("{}w", size), " sizes='3em'")
+                    }
+                };
+                let srcset = thumbnail_paths
+                    .iter()
+                    .map(|(size, thumbnail_path)| {
+                        format!(
+                            "{} {}",
+                            thumbnail_url(thumbnail_path),
+                            descriptor(*size, smallest)
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join(", ");
                 page += &format!(
-                    "><img src='{}?thumbnail={}'>",
-                    config.page_root.as_ref().map(String::as_str).unwrap_or(""),
-                    thumbnail_path
-                        .thumbnail_path()
-                        .file_name()
-                        .map(OsStr::to_string_lossy)
-                        .unwrap_or_else(|| Cow::Borrowed("<broken filename>"))
+                    "><img src='{}' srcset='{}'{}>",
+                    thumbnail_paths
+                        .first()
+                        .map(|(_, thumbnail_path)| thumbnail_url(thumbnail_path))
+                        .unwrap_or_default(),
+                    srcset,
+                    sizes
                 );
             } else {
-                page += ">📃";
+                let extension = path
+                    .local_path()
+                    .extension()
+                    .map(|ext| ext.to_string_lossy().to_lowercase());
+                page += ">";
+                page += &html_escape(config.file_icon_for(extension.as_deref()));
             }
             page += "</div>";
 
             page += "<div class=\"file filename\">";
             page += &format!(
                 "<a href='{}'>{}</a>",
-                ServePath::from_local_path(&self, config, &path)?.to_string(true),
-                basename,
+                html_escape(&ServePath::from_local_path(self, config, &path)?.to_string(true)),
+                html_escape(&basename),
             );
             page += "</div>";
 
@@ -521,8 +1001,13 @@ impl Database {
             page += "</div>\n";
         }
 
-        page += &r#"</div>
-<script type="text/javascript">
+        page += "</div>\n";
+
+        if let Some(readme) = self.readme_for(config, &local_dir) {
+            page += &readme;
+        }
+
+        page += &r#"<script type="text/javascript">
 let sort = null;
 function setSort() {
     if (sort == null) {
@@ -569,22 +1054,20 @@ function filterListForSearchbox() {
     if (everywhere.checked) {
         rows.style.display = 'none';
         for (file of filelist) {
-            if (file.indexOf(searchbox.value) < 0) {
+            if (file.display_path === "" || file.display_path.indexOf(searchbox.value) < 0) {
                 continue;
             }
 
             let div = document.createElement('div');
             div.classList.add('everywheresearch');
 
-            var total = "";
-            for (part of file.split('/')) {
-                if (part === "") {
-                    continue;
-                }
-
-                total += "/" + part;
+            let parts = file.display_path.split('/');
+            let encoded = file.serve_url.split('/');
+            let offset = encoded.length - parts.length;
+            for (let i = 0; i < parts.length; i++) {
+                let part = parts[i];
                 let a = document.createElement('a');
-                a.href = total;
+                a.href = encoded.slice(0, offset + i + 1).join('/');
                 a.appendChild(document.createTextNode(part));
                 div.appendChild(document.createTextNode("/"));
                 div.appendChild(a);
@@ -627,9 +1110,13 @@ searchbox.oninput = filterListForSearchbox;
 </script>"#
             .replace(
                 "Easily the dumbest code I've ever written",
-                config.page_root.as_deref().unwrap_or("/"),
+                &config.root_url(),
             );
 
+        if !use_cache {
+            return Ok(Some(page));
+        }
+
         {
             let mut write = self
                 .pages
@@ -653,6 +1140,57 @@ searchbox.oninput = filterListForSearchbox;
         }
     }
 
+    fn readme_for(&self, config: &Config, local_dir: &LocalPath) -> Option<String> {
+        let (filename, contents) = config.description_files.iter().find_map(|filename| {
+            let path = local_dir.local_path().join(filename);
+            if !path.is_file() {
+                return None;
+            }
+
+            // held to the same rules as walking the file dir
+            let is_symlink = path
+                .symlink_metadata()
+                .is_ok_and(|meta| meta.file_type().is_symlink());
+            if is_symlink && !config.follow_symlinks {
+                tracing::warn!("skipping symlinked description {}", path.display());
+                return None;
+            }
+            if !path
+                .canonicalize()
+                .is_ok_and(|canonical| canonical.starts_with(self.file_dir.local_path()))
+            {
+                tracing::warn!(
+                    "skipping description {}, it's outside the file dir",
+                    path.display()
+                );
+                return None;
+            }
+
+            match std::fs::read_to_string(&path) {
+                Ok(contents) => Some((filename, contents)),
+                Err(e) => {
+                    tracing::warn!("couldn't read description {}: {}", path.display(), e);
+                    None
+                }
+            }
+        })?;
+
+        let is_markdown = Path::new(filename)
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("md"));
+        let html = if is_markdown {
+            markdown_to_html(&contents)
+        } else {
+            format!("<pre>{}</pre>", html_escape(&contents))
+        };
+
+        Some(format!(
+            "<div class=\"readme\"><h2>{}</h2>{}</div>\n",
+            html_escape(filename),
+            html
+        ))
+    }
+
     fn read_config_and_make_dirs(config: &Config) -> Result<Database> {
         let thumbnail_dir = PathBuf::from(&config.thumbnail_dir);
         let thumbnail_dir = LocalPath::from(thumbnail_dir.canonicalize().map_err(|e| {
@@ -702,61 +1240,171 @@ searchbox.oninput = filterListForSearchbox;
         if file_dir.local_path().parent().is_none() {
             return Err(af!("cannot serve files from root dir"));
         }
+        // thumbnails being in the file dir is fine, they're skipped when walking
+        if file_dir
+            .local_path()
+            .starts_with(thumbnail_dir.local_path())
+        {
+            return Err(af!(
+                "file dir {} must not be the thumbnail dir {} or inside it",
+                file_dir.local_path().display(),
+                thumbnail_dir.local_path().display()
+            ));
+        }
+
+        let files = File::walk_dir(
+            &file_dir,
+            0,
+            config.max_depth,
+            config.follow_symlinks,
+            &mut HashSet::new(),
+            &mut 0,
+            &|path| {
+                if !path.starts_with(file_dir.local_path()) {
+                    tracing::warn!("skipping {}, it's outside the file dir", path.display());
+                    return false;
+                }
+                path != thumbnail_dir.local_path()
+            },
+        )?;
+        let (num_files, num_dirs) = File::count(&files);
+        tracing::info!("indexed {} files in {} dirs", num_files, num_dirs);
 
-        let files = File::walk_dir(&file_dir, &|path| path != thumbnail_dir.local_path())?;
-        let thumbnails = build_thumbnail_db(&files, &thumbnail_dir)?;
+        let thumbnails = build_thumbnail_db(&files, &config.thumbnail_sizes, &thumbnail_dir)?;
         Ok(Database {
             file_dir,
             files,
             thumbnail_dir,
             thumbnails,
             pages: Default::default(),
+            watcher_healthy: AtomicBool::new(true),
         })
     }
 
     fn index_and_build_thumbnail_db(&self, config: &Config) -> Result<()> {
-        for (file_path, thumbnail_path) in self.thumbnails.iter() {
-            if !thumbnail_path.thumbnail_path().exists() || config.rebuild_thumbnails {
+        let existing = existing_thumbnails(&self.thumbnail_dir)?;
+        let mut made = 0;
+        for (i, (file_path, thumbnail_paths)) in self.thumbnails.iter().enumerate() {
+            if i > 0 && i % 1000 == 0 {
+                tracing::info!(
+                    "checked {} of {} images, made {} thumbnails so far",
+                    i,
+                    self.thumbnails.len(),
+                    made
+                );
+            }
+
+            let mut missing = thumbnail_paths
+                .iter()
+                .filter(|(_, thumbnail_path)| {
+                    !existing.contains(thumbnail_path.thumbnail_path()) || config.rebuild_thumbnails
+                })
+                .collect::<Vec<_>>();
+            if !config.rebuild_thumbnails {
+                if let Some(migrated) =
+                    self.migrate_old_thumbnail(config, file_path, &missing, &existing)
+                {
+                    missing.retain(|(size, _)| *size != migrated);
+                }
+            }
+            if missing.is_empty() {
+                continue;
+            }
+
+            let image = match ImageReader::open(file_path.local_path())
+                .map_err(|e| {
+                    af!(
+                        "couldn't read file for thumbnailing: {}: {}",
+                        file_path.local_path().display(),
+                        e
+                    )
+                })?
+                .with_guessed_format()
+                .map_err(|e| {
+                    af!(
+                        "couldn't guess format: {}: {}",
+                        file_path.local_path().display(),
+                        e
+                    )
+                })?
+                .decode()
+            {
+                Ok(image) => image,
+                Err(err) => {
+                    tracing::warn!(
+                        "couldn't make thumbnail for {}: {}",
+                        file_path.local_path().display(),
+                        err
+                    );
+                    continue;
+                }
+            };
+
+            for (size, thumbnail_path) in missing {
                 tracing::info!(
                     "making thumbnail for {} -> {}",
                     file_path.local_path().display(),
                     thumbnail_path.thumbnail_path().display()
                 );
 
-                let image = match ImageReader::open(file_path.local_path())
-                    .map_err(|e| {
-                        af!(
-                            "couldn't read file for thumbnailing: {}: {}",
-                            file_path.local_path().display(),
-                            e
-                        )
-                    })?
-                    .with_guessed_format()
-                    .map_err(|e| {
-                        af!(
-                            "couldn't guess format: {}: {}",
-                            file_path.local_path().display(),
-                            e
-                        )
-                    })?
-                    .decode()
-                {
-                    Ok(image) => image,
-                    Err(err) => {
-                        tracing::warn!("couldn't make thumbnail: {}", err);
-                        continue;
+                let (width, height) = (image.width() as f32, image.height() as f32);
+                let thumbnail = match config.thumbnail_shape {
+                    ThumbnailShape::Width => {
+                        let nw = *size;
+                        let nh = ((*size as f32 * (height / width)) as u32).max(1);
+                        tracing::debug!("resizing to {}x{}", nw, nh);
+                        image::imageops::thumbnail(&image, nw, nh)
+                    }
+                    ThumbnailShape::Height => {
+                        let nw = ((*size as f32 * (width / height)) as u32).max(1);
+                        let nh = *size;
+                        tracing::debug!("resizing to {}x{}", nw, nh);
+                        image::imageops::thumbnail(&image, nw, nh)
+                    }
+                    ThumbnailShape::Fixed {
+                        width: box_width,
+                        height: box_height,
+                    } => {
+                        let aspect = box_height as f32 / box_width as f32;
+                        let (crop_width, crop_height) = if height / width > aspect {
+                            (width, width * aspect)
+                        } else {
+                            (height / aspect, height)
+                        };
+                        let x = ((width - crop_width) / 2.0) as u32;
+                        let y = ((height - crop_height) / 2.0) as u32;
+
+                        let nw = *size;
+                        let nh = ((*size as f32 * aspect) as u32).max(1);
+                        tracing::debug!(
+                            "cropping to {}x{}+{}+{}, resizing to {}x{}",
+                            crop_width,
+                            crop_height,
+                            x,
+                            y,
+                            nw,
+                            nh
+                        );
+                        let cropped = image::imageops::crop_imm(
+                            &image,
+                            x,
+                            y,
+                            (crop_width as u32).max(1),
+                            (crop_height as u32).max(1),
+                        );
+                        image::imageops::thumbnail(&*cropped, nw, nh)
                     }
                 };
 
-                let nw = config.thumbnail_size;
-                let nh = (config.thumbnail_size as f32
-                    * (image.height() as f32 / image.width() as f32))
-                    as u32;
-
-                tracing::debug!("resizing to {}x{}", nw, nh);
-                let thumbnail = image::imageops::thumbnail(&image, nw, nh);
+                let [r, g, b] = config.thumbnail_bg_color;
+                let mut background = ImageBuffer::from_pixel(
+                    thumbnail.width(),
+                    thumbnail.height(),
+                    Rgba([r, g, b, 255]),
+                );
+                image::imageops::overlay(&mut background, &thumbnail, 0, 0);
 
-                let converted: ImageBuffer<Rgb<u8>, _> = thumbnail.convert();
+                let converted: ImageBuffer<Rgb<u8>, _> = background.convert();
                 converted
                     .save(thumbnail_path.thumbnail_path())
                     .map_err(|e| {
@@ -767,81 +1415,513 @@ searchbox.oninput = filterListForSearchbox;
                             e
                         )
                     })?;
+                made += 1;
             }
         }
+
+        tracing::info!("made {} thumbnails", made);
         Ok(())
     }
 
-    fn file_list_in(&self, config: &Config, path: &LocalPath) -> Vec<String> {
-        let mut file_path = None;
-        if path == &self.file_dir {
-            file_path = Some(path);
+    /// Thumbnails from before there were several sizes are named by a hash of the image's
+    /// path and were all made at one width, so one of those is renamed to the thumbnail of
+    /// that width instead of being made again. Returns the size it became
+    fn migrate_old_thumbnail(
+        &self,
+        config: &Config,
+        file_path: &LocalPath,
+        missing: &[&(u32, ThumbnailPath)],
+        existing: &HashSet<PathBuf>,
+    ) -> Option<u32> {
+        let old_thumbnail = self
+            .thumbnail_dir
+            .local_path()
+            .join(path_hash(file_path.local_path()))
+            .with_extension("jpg");
+        if !existing.contains(&old_thumbnail)
+            || !matches!(config.thumbnail_shape, ThumbnailShape::Width)
+        {
+            return None;
+        }
+
+        let (old_width, _) = image::image_dimensions(&old_thumbnail)
+            .map_err(|e| {
+                tracing::warn!(
+                    "couldn't read old thumbnail {}: {}",
+                    old_thumbnail.display(),
+                    e
+                )
+            })
+            .ok()?;
+        let (size, thumbnail_path) = missing.iter().find(|(size, _)| *size == old_width)?;
+
+        tracing::info!(
+            "migrating thumbnail {} -> {}",
+            old_thumbnail.display(),
+            thumbnail_path.thumbnail_path().display()
+        );
+        if let Err(e) = std::fs::rename(&old_thumbnail, thumbnail_path.thumbnail_path()) {
+            tracing::warn!(
+                "couldn't rename thumbnail {}: {}",
+                old_thumbnail.display(),
+                e
+            );
+            return None;
+        }
+        Some(*size)
+    }
+
+    fn export_index(&self) -> Vec<IndexEntry> {
+        self.files.iter().map(|file| file.export(self)).collect()
+    }
+
+    fn file_list_in(
+        &self,
+        config: &Config,
+        path: &LocalPath,
+        include_hidden: bool,
+    ) -> Vec<FileListEntry> {
+        // built from the index so it sees exactly what walk_dir let through
+        let children = if path == &self.file_dir {
+            &self.files
         } else {
-            for file in self.files.iter() {
-                if let Some(found) = file.find(path) {
-                    file_path = Some(found.local_path());
-                    break;
-                }
+            match self.find(path) {
+                Some(File::Dir(_, children)) => children,
+                _ => return Vec::with_capacity(0),
             }
-        }
-        let Some(thefile) = file_path else {
-            return Vec::with_capacity(0);
         };
 
         let mut list = Vec::new();
-        fn walk(list: &mut Vec<String>, db: &Database, config: &Config, path: &LocalPath) {
+        fn push(list: &mut Vec<FileListEntry>, db: &Database, config: &Config, path: &LocalPath) {
             let Ok(serve) = ServePath::from_local_path(db, config, path) else {
                 // TODO error xdd
                 tracing::error!("serve path");
                 return;
             };
 
-            if path == &db.thumbnail_dir {
-                return;
-            }
-
-            list.push(serve.to_string(false));
-            if path.local_path().is_dir() {
-                let Ok(readdir) = path.local_path().read_dir() else {
-                    // TODO error xdd
-                    tracing::error!("couldn't read dir");
-                    return;
-                };
-                for child in readdir {
-                    let Ok(child) = child else {
-                        // TODO error xdd
-                        tracing::error!("couldn't read entry");
-                        continue;
-                    };
+            let display_path = path
+                .local_path()
+                .strip_prefix(db.file_dir.local_path())
+                .unwrap_or(path.local_path())
+                .components()
+                .map(|part| part.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            list.push(FileListEntry {
+                display_path,
+                serve_url: serve.to_string(true),
+            });
+        }
+        fn walk(
+            list: &mut Vec<FileListEntry>,
+            db: &Database,
+            config: &Config,
+            files: &[File],
+            include_hidden: bool,
+        ) {
+            for file in files {
+                if !include_hidden && file.local_path().is_hidden() {
+                    continue;
+                }
 
-                    walk(list, db, config, &LocalPath(child.path()));
+                push(list, db, config, file.local_path());
+                if let File::Dir(_, children) = file {
+                    walk(list, db, config, children, include_hidden);
                 }
             }
         }
-        walk(&mut list, self, config, thefile);
+
+        push(&mut list, self, config, path);
+        walk(&mut list, self, config, children, include_hidden);
         list
     }
 }
 
-#[derive(Debug)]
-pub struct Config {
-    bind: String,
-    auth: Option<String>,
-    thumbnail_dir: String,
+#[derive(serde::Serialize)]
+struct FileListEntry {
+    display_path: String,
+    serve_url: String,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ThumbnailShape {
+    /// Sizes are widths, heights follow the image's aspect ratio
+    Width,
+    /// Sizes are heights, widths follow the image's aspect ratio
+    Height,
+    /// Sizes are widths, images are center-cropped to this aspect ratio
+    Fixed { width: u32, height: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TrailingSlashMode {
+    /// Redirect page_root to page_root/
+    Add,
+    /// Redirect page_root/ to page_root
+    Remove,
+    /// Serve both without redirecting
+    Ignore,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    bind: String,
+    auth: Option<String>,
+    thumbnail_dir: String,
     file_dir: String,
-    thumbnail_size: u32,
+    thumbnail_sizes: Vec<u32>,
+    thumbnail_shape: ThumbnailShape,
+    thumbnail_bg_color: [u8; 3],
+    description_files: Vec<String>,
     rebuild_thumbnails: bool,
     page_root: Option<String>,
     auth_realm: Option<String>,
+    auth_message: Option<String>,
+    not_found_message: Option<String>,
+    page_title: String,
+    favicon_path: Option<PathBuf>,
+    admin_token: Option<String>,
+    cache_policy: String,
+    extension_cache_policies: HashMap<String, String>,
+    file_icons: HashMap<String, String>,
+    dir_cache_policy: Option<String>,
+    no_cache_patterns: Vec<glob::Pattern>,
+    trusted_proxy_cidrs: Vec<ipnet::IpNet>,
+    show_panic_messages: bool,
+    trailing_slash: TrailingSlashMode,
+    on_scan_complete: Option<String>,
+    on_scan_hook_timeout: u64,
     cache_clear_interval: u64,
+    max_depth: Option<u32>,
+    follow_symlinks: bool,
 }
 
 impl Config {
-    fn read_from(config_path: &str) -> Result<Config> {
+    const MIN_CACHE_CLEAR_INTERVAL: u64 = 1;
+    const ENV_OVERRIDES: &'static [(&'static str, &'static str)] = &[
+        ("DOP_BIND", "bind"),
+        ("DOP_FILE_DIR", "file_dir"),
+        ("DOP_THUMBNAIL_DIR", "thumbnail_dir"),
+    ];
+    const RELATIVE_TO_CONFIG: &'static [&'static str] =
+        &["thumbnail_dir", "file_dir", "favicon_path"];
+    const CACHE_POLICY: &'static str = "private, max-age=3600, must-revalidate";
+    const NO_CACHE_POLICY: &'static str = "no-store, no-cache, must-revalidate, private";
+    const TEMPLATE: &'static str = r#"# address to listen on (or DOP_BIND)
+bind = "127.0.0.1:8888"
+# directory to serve, relative paths are relative to this file (or DOP_FILE_DIR)
+file_dir = "files"
+# where thumbnails are kept, relative paths are relative to this file (or DOP_THUMBNAIL_DIR)
+thumbnail_dir = "thumbnails"
+
+# serve everything under this path instead of /
+#page_root = "/files"
+# whether to redirect page_root to page_root/ (add), the other way (remove), or neither (ignore)
+#trailing_slash = "remove"
+# stop indexing below this many directory levels, unlimited by default (but never past 500)
+#max_depth = 20
+# follow symlinks inside file_dir, links pointing outside it are always skipped
+#follow_symlinks = false
+
+# thumbnail widths to generate, the listing picks one with srcset
+#thumbnail_sizes = [75, 150, 300]
+# set width, height, or both to crop thumbnails to a fixed box
+#thumbnail_width = 150
+#thumbnail_height = 150
+# upper limit for all thumbnail dimensions
+#max_thumbnail_size = 4096
+# background for transparent images, as rgb
+#thumbnail_bg_color = [255, 255, 255]
+
+# first of these found in a directory is shown below its listing
+#description_files = ["README.md", "README.txt", "description.txt"]
+
+# require http basic auth, as "user:password"
+#auth = "user:password"
+#auth_realm = "dop"
+#auth_message = "need auth!"
+
+#page_title = "dop"
+# relative paths are relative to this file
+#favicon_path = "favicon.png"
+#not_found_message = "skill issue"
+# show panic messages in error pages instead of a generic 500
+#show_panic_messages = false
+
+# token for /.dop/admin/export-index, sent as X-Admin-Token
+#admin_token = "..."
+# use X-Forwarded-For/X-Real-IP from these proxies
+#trusted_proxy_cidrs = ["127.0.0.1/32"]
+
+# Cache-Control for served files and directory listings
+#cache_policy = "private, max-age=3600, must-revalidate"
+#dir_cache_policy = "no-cache"
+# never cache paths under file_dir matching these globs, or anything inside them
+#no_cache_patterns = ["private/**"]
+# seconds between clearing the listing cache
+#cache_clear_interval = 3600
+
+# shell command run once the startup scan is done, and its timeout in seconds
+#on_scan_complete = "echo done"
+#on_scan_hook_timeout = 10
+
+# Cache-Control per file extension
+#[extension_cache_policies]
+#html = "no-cache"
+
+# icons for files without thumbnails, per file extension
+#[file_icons]
+#pdf = "📕"
+"#;
+    const FILE_ICON: &'static str = "📃";
+    const FILE_ICONS: &'static [(&'static [&'static str], &'static str)] = &[
+        (&["pdf"], "📕"),
+        (&["zip", "tar", "gz", "xz", "zst", "7z", "rar"], "📦"),
+        (&["mp4", "mkv", "webm", "mov", "avi"], "🎞️"),
+        (&["mp3", "flac", "ogg", "opus", "wav", "m4a"], "🎵"),
+        (&["txt", "md"], "📝"),
+    ];
+
+    fn validate_cache_policy(policy: &str) -> Result<()> {
+        for directive in policy.split(',').map(str::trim) {
+            let (name, value) = directive
+                .split_once('=')
+                .map(|(name, value)| (name, Some(value)))
+                .unwrap_or((directive, None));
+
+            let is_token = |s: &str| {
+                !s.is_empty()
+                    && s.chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+            };
+            let is_quoted = |s: &str| {
+                s.len() >= 2
+                    && s.starts_with('"')
+                    && s.ends_with('"')
+                    && s[1..s.len() - 1].is_ascii()
+            };
+
+            if !is_token(name) || value.is_some_and(|value| !is_token(value) && !is_quoted(value)) {
+                return Err(af!(
+                    "invalid cache-control directive {:?} in {:?}",
+                    directive,
+                    policy
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn client_ip(&self, request: &rouille::Request) -> std::net::IpAddr {
+        let peer = request.remote_addr().ip();
+        if !self
+            .trusted_proxy_cidrs
+            .iter()
+            .any(|cidr| cidr.contains(&peer))
+        {
+            return peer;
+        }
+
+        request
+            .header("X-Forwarded-For")
+            .and_then(|forwarded| forwarded.split(',').next())
+            .or_else(|| request.header("X-Real-IP"))
+            .and_then(|ip| ip.trim().parse().ok())
+            .unwrap_or(peer)
+    }
+
+    /// Link to the top of the listing, in the form trailing_slash redirects to
+    fn root_url(&self) -> String {
+        match (self.page_root.as_ref(), self.trailing_slash) {
+            (Some(root), TrailingSlashMode::Add) => format!("{}/", root),
+            (Some(root), _) => root.clone(),
+            (None, _) => String::from("/"),
+        }
+    }
+
+    fn cache_policy_for(&self, extension: Option<&str>) -> &str {
+        extension
+            .and_then(|ext| self.extension_cache_policies.get(ext))
+            .unwrap_or(&self.cache_policy)
+    }
+
+    fn is_no_cache(&self, relative_path: &Path) -> bool {
+        relative_path.ancestors().any(|path| {
+            self.no_cache_patterns
+                .iter()
+                .any(|pattern| pattern.matches_path(path))
+        })
+    }
+
+    fn file_icon_for(&self, extension: Option<&str>) -> &str {
+        extension
+            .and_then(|ext| self.file_icons.get(ext))
+            .map(String::as_str)
+            .unwrap_or(Self::FILE_ICON)
+    }
+
+    fn read_toml(config_path: &str) -> Result<toml::Value> {
         let config_file = std::fs::read_to_string(config_path)
             .map_err(|_| af!("can't read config file {}", config_path))?;
-        let toml = toml::from_str::<toml::Value>(&config_file)
+        // editors on windows like to save with a bom, which toml doesn't accept
+        let config_file = match config_file.strip_prefix('\u{FEFF}') {
+            Some(stripped) => {
+                tracing::info!("ignoring byte order mark in config file {}", config_path);
+                stripped
+            }
+            None => &config_file,
+        };
+        let mut toml = toml::from_str::<toml::Value>(config_file)
             .map_err(|e| af!("couldn't read config file {}:\n{:#?}", config_path, e))?;
+        Self::expand_env(&mut toml, config_path)?;
+
+        let config_dir = Path::new(config_path).parent().unwrap_or(Path::new("."));
+        if let Some(table) = toml.as_table_mut() {
+            for key in Self::RELATIVE_TO_CONFIG {
+                if let Some(toml::Value::String(path)) = table.get_mut(*key) {
+                    if Path::new(path).is_relative() {
+                        *path = config_dir.join(&path).display().to_string();
+                    }
+                }
+            }
+        }
+
+        Ok(toml)
+    }
+
+    /// Replaces `${VAR}` in string values with the environment variable, `$${` is a literal `${`
+    fn expand_env(value: &mut toml::Value, config_path: &str) -> Result<()> {
+        match value {
+            toml::Value::String(string) if string.contains("${") => {
+                let mut expanded = String::new();
+                let mut rest = string.as_str();
+                while let Some(start) = rest.find('$') {
+                    expanded += &rest[..start];
+                    rest = &rest[start..];
+                    if let Some(after) = rest.strip_prefix("$${") {
+                        expanded += "${";
+                        rest = after;
+                    } else if let Some(after) = rest.strip_prefix("${") {
+                        let end = after.find('}').ok_or_else(|| {
+                            af!("unterminated ${{ in config file {}", config_path)
+                        })?;
+                        let var = &after[..end];
+                        expanded += &std::env::var(var).map_err(|_| {
+                            af!(
+                                "environment variable {} referenced in config file {} but not set",
+                                var,
+                                config_path
+                            )
+                        })?;
+                        rest = &after[end + 1..];
+                    } else {
+                        expanded += "$";
+                        rest = &rest[1..];
+                    }
+                }
+                expanded += rest;
+                *string = expanded;
+            }
+            toml::Value::Array(values) => {
+                for value in values {
+                    Self::expand_env(value, config_path)?;
+                }
+            }
+            toml::Value::Table(table) => {
+                for (_, value) in table.iter_mut() {
+                    Self::expand_env(value, config_path)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Tables are merged key by key, anything else in the overlay replaces the base
+    fn merge_toml(base: &mut toml::Value, overlay: toml::Value) {
+        match (base, overlay) {
+            (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+                for (key, value) in overlay {
+                    match base.get_mut(&key) {
+                        Some(existing) => Self::merge_toml(existing, value),
+                        None => {
+                            base.insert(key, value);
+                        }
+                    }
+                }
+            }
+            (base, overlay) => *base = overlay,
+        }
+    }
+
+    /// `/a/b/` and `a/b` become `/a/b`, and `/` means no page root
+    fn parse_page_root(page_root: &str, config_path: &str) -> Result<Option<String>> {
+        let page_root = page_root.trim_matches('/');
+        if page_root.is_empty() {
+            return Ok(None);
+        }
+
+        for (i, segment) in page_root.split('/').enumerate() {
+            if segment.is_empty()
+                || segment == "."
+                || segment == ".."
+                || segment.contains(['?', '#', '%'])
+            {
+                return Err(af!(
+                    "page_root /{} has invalid segment {:?} in config file {}",
+                    page_root,
+                    segment,
+                    config_path
+                ));
+            }
+            if i == 0 && segment == ".dop" {
+                return Err(af!(
+                    "page_root /{} is under /.dop, which is reserved, in config file {}",
+                    page_root,
+                    config_path
+                ));
+            }
+        }
+
+        Ok(Some(format!("/{}", page_root)))
+    }
+
+    fn read_from(config_path: &str, overlay_paths: &[String]) -> Result<Config> {
+        let mut toml = Self::read_toml(config_path)?;
+        for overlay_path in overlay_paths {
+            tracing::info!("applying config overlay {}", overlay_path);
+            Self::merge_toml(&mut toml, Self::read_toml(overlay_path)?);
+        }
+
+        for (var, key) in Self::ENV_OVERRIDES {
+            if let Ok(value) = std::env::var(var) {
+                tracing::info!("using {} from {} instead of config file", key, var);
+                if let Some(table) = toml.as_table_mut() {
+                    table.insert(key.to_string(), toml::Value::String(value));
+                }
+            }
+        }
+
+        let (config, read_keys) = Self::from_toml(&toml, config_path)?;
+        if let Some(table) = toml.as_table() {
+            for key in table.keys().filter(|key| !read_keys.contains(*key)) {
+                tracing::warn!(
+                    "ignoring unknown key {} in config file {}",
+                    key,
+                    config_path
+                );
+            }
+        }
+        Ok(config)
+    }
+
+    /// Also gives back every key that was looked up, whether or not it was set
+    fn from_toml(toml: &toml::Value, config_path: &str) -> Result<(Config, HashSet<String>)> {
+        let toml = SeenKeys {
+            toml,
+            seen: Default::default(),
+        };
 
         let thumbnail_dir = toml
             .get("thumbnail_dir")
@@ -862,34 +1942,129 @@ impl Config {
             .ok_or_else(|| af!("file dir must be string in config file {}", config_path))?
             .to_string();
 
-        let thumbnail_size = toml
-            .get("thumbnail_size")
-            .map(|size| match size {
-                toml::Value::Integer(value) => (*value)
-                    .try_into()
-                    .map_err(|_| af!("thumbnail size must fit in u32")),
-                _ => Err(af!("thumbnail size must be integer")),
+        let thumbnail_dimension = |key: &str| {
+            toml.get(key)
+                .map(|size| match size {
+                    toml::Value::Integer(value) => (*value)
+                        .try_into()
+                        .map_err(|_| af!("{} must fit in u32", key)),
+                    _ => Err(af!("{} must be integer", key)),
+                })
+                .transpose()
+        };
+        let thumbnail_width = match thumbnail_dimension("thumbnail_width")? {
+            Some(width) => Some(width),
+            None => thumbnail_dimension("thumbnail_size")?,
+        };
+        let thumbnail_height = thumbnail_dimension("thumbnail_height")?;
+        let max_thumbnail_size = thumbnail_dimension("max_thumbnail_size")?.unwrap_or(4096);
+        if max_thumbnail_size == 0 {
+            return Err(af!("max_thumbnail_size must be greater than 0"));
+        }
+        for (key, size) in [
+            ("thumbnail_width", thumbnail_width),
+            ("thumbnail_height", thumbnail_height),
+        ] {
+            if size.is_some_and(|size| size == 0 || size > max_thumbnail_size) {
+                return Err(af!("{} must be between 1 and {}", key, max_thumbnail_size));
+            }
+        }
+        let thumbnail_shape = match (thumbnail_width, thumbnail_height) {
+            (Some(width), Some(height)) => ThumbnailShape::Fixed { width, height },
+            (None, Some(_)) => ThumbnailShape::Height,
+            _ => ThumbnailShape::Width,
+        };
+
+        let thumbnail_sizes = toml
+            .get("thumbnail_sizes")
+            .map(|sizes| {
+                sizes
+                    .as_array()
+                    .ok_or_else(|| af!("thumbnail_sizes must be an array"))?
+                    .iter()
+                    .map(|size| match size {
+                        toml::Value::Integer(value) => (*value)
+                            .try_into()
+                            .map_err(|_| af!("thumbnail size must fit in u32")),
+                        _ => Err(af!("thumbnail size must be integer")),
+                    })
+                    .collect::<Result<Vec<u32>>>()
+            })
+            .transpose()?;
+        let mut thumbnail_sizes = match (thumbnail_sizes, thumbnail_width.or(thumbnail_height)) {
+            (Some(sizes), _) => sizes,
+            (None, Some(size)) => vec![size],
+            (None, None) => vec![75, 150, 300],
+        };
+        thumbnail_sizes.sort();
+        thumbnail_sizes.dedup();
+        if thumbnail_sizes.is_empty() {
+            return Err(af!("thumbnail_sizes must not be empty"));
+        }
+        if thumbnail_sizes
+            .iter()
+            .any(|&size| size == 0 || size > max_thumbnail_size)
+        {
+            return Err(af!(
+                "thumbnail sizes must be between 1 and {}",
+                max_thumbnail_size
+            ));
+        }
+
+        let thumbnail_bg_color = toml
+            .get("thumbnail_bg_color")
+            .map(|color| {
+                let color = color
+                    .as_array()
+                    .ok_or_else(|| af!("thumbnail_bg_color must be an array"))?
+                    .iter()
+                    .map(|component| match component {
+                        toml::Value::Integer(value) => (*value)
+                            .try_into()
+                            .map_err(|_| af!("thumbnail_bg_color components must be 0-255")),
+                        _ => Err(af!("thumbnail_bg_color components must be integers")),
+                    })
+                    .collect::<Result<Vec<u8>>>()?;
+                <[u8; 3]>::try_from(color)
+                    .map_err(|_| af!("thumbnail_bg_color must have exactly 3 components"))
             })
             .transpose()?
-            .unwrap_or(75);
+            .unwrap_or([255, 255, 255]);
+
+        let description_files = toml
+            .get("description_files")
+            .map(|files| {
+                files
+                    .as_array()
+                    .ok_or_else(|| af!("description_files must be an array"))?
+                    .iter()
+                    .map(|file| {
+                        file.as_str()
+                            .map(String::from)
+                            .ok_or_else(|| af!("description file names must be strings"))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?
+            .unwrap_or_else(|| {
+                ["README.md", "README.txt", "description.txt"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect()
+            });
 
         let page_root = toml
             .get("page_root")
             .map(|page| {
                 page.as_str()
-                    .map(String::from)
-                    .map(|value| {
-                        if !value.starts_with("/") {
-                            format!("/{}", value)
-                        } else {
-                            value
-                        }
-                    })
                     .ok_or_else(|| {
                         af!("page_root must be a string in config file {}", config_path)
                     })
             })
-            .transpose()?;
+            .transpose()?
+            .map(|page_root| Self::parse_page_root(page_root, config_path))
+            .transpose()?
+            .flatten();
 
         let auth = toml
             .get("auth")
@@ -916,45 +2091,491 @@ impl Config {
                     .ok_or_else(|| af!("auth_realm must be a string"))
             })
             .transpose()?;
+        if let Some(realm) = auth_realm.as_ref() {
+            if realm
+                .chars()
+                .any(|c| c == '"' || c == '\\' || !c.is_ascii() || c.is_ascii_control())
+            {
+                return Err(af!(
+                    "auth_realm must be printable ASCII without quotes or backslashes in config file {}",
+                    config_path
+                ));
+            }
+        }
+
+        let auth_message = toml
+            .get("auth_message")
+            .map(|message| {
+                message
+                    .as_str()
+                    .map(String::from)
+                    .ok_or_else(|| af!("auth_message must be a string"))
+            })
+            .transpose()?;
+
+        let not_found_message = toml
+            .get("not_found_message")
+            .map(|message| {
+                message
+                    .as_str()
+                    .map(String::from)
+                    .ok_or_else(|| af!("not_found_message must be a string"))
+            })
+            .transpose()?;
+
+        let page_title = toml
+            .get("page_title")
+            .map(|title| {
+                title
+                    .as_str()
+                    .map(String::from)
+                    .ok_or_else(|| af!("page_title must be a string"))
+            })
+            .transpose()?
+            .unwrap_or_else(|| String::from("dop"));
+
+        let favicon_path = toml
+            .get("favicon_path")
+            .map(|path| {
+                path.as_str()
+                    .map(PathBuf::from)
+                    .ok_or_else(|| af!("favicon_path must be a string"))
+            })
+            .transpose()?;
+        if let Some(path) = favicon_path.as_ref() {
+            if let Err(e) = FsFile::open(path) {
+                tracing::warn!("couldn't read favicon {}: {}", path.display(), e);
+            }
+        }
+
+        let admin_token = toml
+            .get("admin_token")
+            .map(|token| {
+                token
+                    .as_str()
+                    .map(String::from)
+                    .ok_or_else(|| af!("admin_token must be a string"))
+            })
+            .transpose()?;
+
+        let cache_policy = toml
+            .get("cache_policy")
+            .map(|policy| {
+                policy
+                    .as_str()
+                    .map(String::from)
+                    .ok_or_else(|| af!("cache_policy must be a string"))
+            })
+            .transpose()?
+            .unwrap_or_else(|| String::from(Self::CACHE_POLICY));
+        Self::validate_cache_policy(&cache_policy)?;
+
+        let extension_cache_policies = toml
+            .get("extension_cache_policies")
+            .map(|policies| {
+                policies
+                    .as_table()
+                    .ok_or_else(|| af!("extension_cache_policies must be a table"))?
+                    .iter()
+                    .map(|(ext, policy)| {
+                        let policy = policy.as_str().ok_or_else(|| {
+                            af!("cache policy for extension {} must be a string", ext)
+                        })?;
+                        Self::validate_cache_policy(policy)?;
+                        Ok((ext.to_lowercase(), policy.to_string()))
+                    })
+                    .collect::<Result<HashMap<_, _>>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        let mut file_icons = Self::FILE_ICONS
+            .iter()
+            .flat_map(|(exts, icon)| exts.iter().map(|ext| (ext.to_string(), icon.to_string())))
+            .collect::<HashMap<_, _>>();
+        if let Some(icons) = toml.get("file_icons") {
+            for (ext, icon) in icons
+                .as_table()
+                .ok_or_else(|| af!("file_icons must be a table"))?
+            {
+                let icon = icon
+                    .as_str()
+                    .ok_or_else(|| af!("icon for extension {} must be a string", ext))?;
+                file_icons.insert(ext.to_lowercase(), icon.to_string());
+            }
+        }
+
+        let dir_cache_policy = toml
+            .get("dir_cache_policy")
+            .map(|policy| {
+                let policy = policy
+                    .as_str()
+                    .ok_or_else(|| af!("dir_cache_policy must be a string"))?;
+                Self::validate_cache_policy(policy)?;
+                Ok::<_, anyhow::Error>(policy.to_string())
+            })
+            .transpose()?;
+
+        let no_cache_patterns = toml
+            .get("no_cache_patterns")
+            .map(|patterns| {
+                patterns
+                    .as_array()
+                    .ok_or_else(|| af!("no_cache_patterns must be an array"))?
+                    .iter()
+                    .map(|pattern| {
+                        let pattern = pattern
+                            .as_str()
+                            .ok_or_else(|| af!("no cache pattern must be a string"))?;
+                        glob::Pattern::new(pattern.trim_matches('/'))
+                            .map_err(|e| af!("invalid no cache pattern {}: {}", pattern, e))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+
+        let trusted_proxy_cidrs = toml
+            .get("trusted_proxy_cidrs")
+            .map(|cidrs| {
+                cidrs
+                    .as_array()
+                    .ok_or_else(|| af!("trusted_proxy_cidrs must be an array"))?
+                    .iter()
+                    .map(|cidr| {
+                        let cidr = cidr
+                            .as_str()
+                            .ok_or_else(|| af!("trusted proxy CIDR must be a string"))?;
+                        cidr.parse::<ipnet::IpNet>()
+                            .map_err(|e| af!("invalid trusted proxy CIDR {}: {}", cidr, e))
+                    })
+                    .collect::<Result<Vec<_>>>()
+            })
+            .transpose()?
+            .unwrap_or_default();
+        for cidr in trusted_proxy_cidrs.iter() {
+            let is_private = |ip: std::net::IpAddr| match ip {
+                std::net::IpAddr::V4(ip) => {
+                    ip.is_private() || ip.is_loopback() || ip.is_link_local()
+                }
+                std::net::IpAddr::V6(ip) => {
+                    ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local()
+                }
+            };
+            if !is_private(cidr.network()) || !is_private(cidr.broadcast()) {
+                tracing::warn!(
+                    "trusted proxy range {} includes non-private addresses, clients in it can spoof their IP",
+                    cidr
+                );
+            }
+        }
+
+        let show_panic_messages = toml
+            .get("show_panic_messages")
+            .map(|show| {
+                show.as_bool()
+                    .ok_or_else(|| af!("show_panic_messages must be a boolean"))
+            })
+            .transpose()?
+            .unwrap_or(false);
+
+        let trailing_slash = toml
+            .get("trailing_slash")
+            .map(|mode| match mode.as_str() {
+                Some("add") => Ok(TrailingSlashMode::Add),
+                Some("remove") => Ok(TrailingSlashMode::Remove),
+                Some("ignore") => Ok(TrailingSlashMode::Ignore),
+                _ => Err(af!("trailing_slash must be one of add, remove, ignore")),
+            })
+            .transpose()?
+            .unwrap_or(TrailingSlashMode::Remove);
+
+        let on_scan_complete = toml
+            .get("on_scan_complete")
+            .map(|command| {
+                command
+                    .as_str()
+                    .map(String::from)
+                    .ok_or_else(|| af!("on_scan_complete must be a string"))
+            })
+            .transpose()?;
+
+        let on_scan_hook_timeout = toml
+            .get("on_scan_hook_timeout")
+            .map(|timeout| match timeout {
+                toml::Value::Integer(value) => (*value)
+                    .try_into()
+                    .map_err(|_| af!("on_scan_hook_timeout must not be negative")),
+                _ => Err(af!("on_scan_hook_timeout must be integer")),
+            })
+            .transpose()?
+            .unwrap_or(10);
 
         let cache_clear_interval = toml
             .get("cache_clear_interval")
-            .iter()
-            .flat_map(|cci| cci.as_integer())
-            .next()
-            .unwrap_or(60 * 60) as u64;
+            .map(|cci| match cci {
+                toml::Value::Integer(value) => (*value)
+                    .try_into()
+                    .map_err(|_| af!("cache_clear_interval must not be negative")),
+                _ => Err(af!("cache_clear_interval must be integer")),
+            })
+            .transpose()?
+            .unwrap_or(60 * 60);
+        if cache_clear_interval < Self::MIN_CACHE_CLEAR_INTERVAL {
+            return Err(af!(
+                "cache_clear_interval must be at least {} second(s), got {} in config file {}",
+                Self::MIN_CACHE_CLEAR_INTERVAL,
+                cache_clear_interval,
+                config_path
+            ));
+        }
+        if cache_clear_interval < 10 {
+            tracing::warn!(
+                "cache_clear_interval of {}s is very short, directory pages will be rebuilt constantly",
+                cache_clear_interval
+            );
+        }
+
+        let max_depth = toml
+            .get("max_depth")
+            .map(|depth| match depth {
+                toml::Value::Integer(value) => (*value)
+                    .try_into()
+                    .map_err(|_| af!("max_depth must fit in u32")),
+                _ => Err(af!("max_depth must be integer")),
+            })
+            .transpose()?;
+
+        let follow_symlinks = toml
+            .get("follow_symlinks")
+            .map(|follow| {
+                follow
+                    .as_bool()
+                    .ok_or_else(|| af!("follow_symlinks must be a boolean"))
+            })
+            .transpose()?
+            .unwrap_or(false);
 
-        Ok(Config {
+        let config = Config {
             bind,
             auth,
             thumbnail_dir,
             file_dir,
-            thumbnail_size,
+            thumbnail_sizes,
+            thumbnail_shape,
+            thumbnail_bg_color,
+            description_files,
             rebuild_thumbnails: false,
             page_root,
             auth_realm,
+            auth_message,
+            not_found_message,
+            page_title,
+            favicon_path,
+            admin_token,
+            cache_policy,
+            extension_cache_policies,
+            file_icons,
+            dir_cache_policy,
+            no_cache_patterns,
+            trusted_proxy_cidrs,
+            show_panic_messages,
+            trailing_slash,
+            on_scan_complete,
+            on_scan_hook_timeout,
             cache_clear_interval,
+            max_depth,
+            follow_symlinks,
+        };
+        Ok((config, toml.seen.into_inner()))
+    }
+}
+
+/// Remembers which keys were looked up, so anything left over can be reported
+struct SeenKeys<'a> {
+    toml: &'a toml::Value,
+    seen: std::cell::RefCell<HashSet<String>>,
+}
+
+impl<'a> SeenKeys<'a> {
+    fn get(&self, key: &str) -> Option<&'a toml::Value> {
+        self.seen.borrow_mut().insert(key.to_string());
+        self.toml.get(key)
+    }
+}
+
+fn run_hook(command: &str, timeout: u64) -> Result<()> {
+    use std::io::Read;
+    use std::process::{Command, Stdio};
+
+    tracing::info!("running hook: {}", command);
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let mut child = shell
+        .arg(command)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| af!("couldn't run hook {}: {}", command, e))?;
+
+    let read_all = |pipe: Option<Box<dyn Read + Send>>| {
+        std::thread::spawn(move || {
+            let mut output = String::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_string(&mut output);
+            }
+            output
         })
+    };
+    let stdout = read_all(child.stdout.take().map(|p| Box::new(p) as _));
+    let stderr = read_all(child.stderr.take().map(|p| Box::new(p) as _));
+
+    let start = std::time::Instant::now();
+    let status = loop {
+        if let Some(status) = child
+            .try_wait()
+            .map_err(|e| af!("couldn't wait for hook: {}", e))?
+        {
+            break Some(status);
+        }
+        if start.elapsed() > std::time::Duration::from_secs(timeout) {
+            let _ = child.kill();
+            let _ = child.wait();
+            break None;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    };
+
+    // anything the hook spawned may still hold the pipes open after a timeout,
+    // so only collect the output if it exited on its own
+    let Some(status) = status else {
+        return Err(af!("hook {} timed out after {}s", command, timeout));
+    };
+
+    tracing::debug!("hook stdout: {}", stdout.join().unwrap_or_default());
+    tracing::debug!("hook stderr: {}", stderr.join().unwrap_or_default());
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(af!("hook {} exited with {}", command, status))
     }
 }
 
+fn check(config: &Config, db: &Database) -> Result<()> {
+    let probe = db.thumbnail_dir.local_path().join(".dop-check");
+    std::fs::write(&probe, b"").map_err(|e| {
+        af!(
+            "thumbnail dir {} is not writable: {}",
+            db.thumbnail_dir.local_path().display(),
+            e
+        )
+    })?;
+    std::fs::remove_file(&probe).map_err(|e| af!("couldn't remove {}: {}", probe.display(), e))?;
+
+    println!("config ok");
+    println!("  bind: {}", config.bind);
+    println!("  file dir: {}", db.file_dir.local_path().display());
+    println!(
+        "  thumbnail dir: {}",
+        db.thumbnail_dir.local_path().display()
+    );
+    println!(
+        "  page root: {}",
+        config.page_root.as_deref().unwrap_or("(none)")
+    );
+    println!("  thumbnail sizes: {:?}", config.thumbnail_sizes);
+    println!("  cache clear interval: {}s", config.cache_clear_interval);
+    println!("  auth enabled: {}", config.auth.is_some());
+    println!("  admin enabled: {}", config.admin_token.is_some());
+    println!("  thumbnailable files: {}", db.thumbnails.len());
+
+    Ok(())
+}
+
 fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let log_level = std::env::var("DOP_LOG_LEVEL")
+        .ok()
+        .map(|level| level.parse::<tracing_subscriber::filter::LevelFilter>());
+    let log_format = std::env::var("DOP_LOG_FORMAT").ok();
+    let subscriber = tracing_subscriber::fmt().with_max_level(match log_level {
+        Some(Ok(level)) => level,
+        _ => tracing_subscriber::filter::LevelFilter::INFO,
+    });
+    match log_format.as_deref() {
+        Some("json") => subscriber.json().with_current_span(true).init(),
+        _ => subscriber.init(),
+    }
+    if let Some(Err(e)) = log_level {
+        return Err(af!("invalid DOP_LOG_LEVEL: {}", e));
+    }
+    if let Some(format) = log_format.filter(|format| format != "text" && format != "json") {
+        return Err(af!(
+            "invalid DOP_LOG_FORMAT: {}, must be text or json",
+            format
+        ));
+    }
+
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    // the flags from before there were subcommands still work without one
+    let command = match args.first().map(String::as_str) {
+        Some("serve" | "check" | "dump-config" | "version") => args.remove(0),
+        _ => String::from("serve"),
+    };
+    let flag = |name: &str| args.iter().any(|arg| arg == name);
+    let mut config_path = None;
+    let mut overlay_paths = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next() {
+        if arg == "--config-overlay" {
+            overlay_paths.push(
+                rest.next()
+                    .ok_or_else(|| af!("--config-overlay needs a path"))?
+                    .clone(),
+            );
+        } else if !arg.starts_with("--") && config_path.is_none() {
+            config_path = Some(arg);
+        }
+    }
+    if command == "version" || flag("--version") {
+        println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+        return Ok(());
+    }
+    if command == "dump-config" || flag("--dump-config") {
+        print!("{}", Config::TEMPLATE);
+        return Ok(());
+    }
 
-    let args = std::env::args().collect::<Vec<_>>();
     let mut config = Config::read_from(
-        args.get(1)
-            .ok_or_else(|| af!("need config file argument"))?,
+        config_path.ok_or_else(|| af!("need config file argument"))?,
+        &overlay_paths,
     )?;
     let database = Database::read_config_and_make_dirs(&config)?;
 
+    if command == "check" || flag("--check") {
+        return check(&config, &database);
+    }
+
     tracing::debug!("{:#?}", config);
     tracing::debug!("{:#?}", database);
     tracing::info!("checking thumbnail database");
 
-    config.rebuild_thumbnails = Some("--rebuild-thumbnails") == args.get(2).map(|s| &**s);
+    config.rebuild_thumbnails = flag("--rebuild-thumbnails");
     database.index_and_build_thumbnail_db(&config)?;
 
+    if let Some(command) = config.on_scan_complete.as_ref() {
+        if let Err(e) = run_hook(command, config.on_scan_hook_timeout) {
+            tracing::error!("scan completion hook failed: {}", e);
+        }
+    }
+
     tracing::info!("starting! binding to {}", config.bind);
 
     // hmmmmmmm
@@ -963,221 +2584,1277 @@ fn main() -> Result<()> {
     let file_dir = config.file_dir.clone();
     let thumb_dir = Box::leak(Box::new(db.thumbnail_dir.clone()));
     std::thread::spawn(move || {
-        let mut watcher = notify::recommended_watcher(|r: Result<notify::Event, _>| match r {
-            Ok(ev) => {
-                for path in ev
-                    .paths
-                    .iter()
-                    .filter(|path| !path.starts_with(thumb_dir.local_path()))
-                {
-                    tracing::info!("clearing cache for {}, got fs update", path.display());
-                    if db.clear_cache_for(path).is_err() {
-                        tracing::error!("could not clear cache");
+        let watch = || {
+            let mut watcher = notify::recommended_watcher(|r: Result<notify::Event, _>| match r {
+                Ok(ev) => {
+                    for path in ev
+                        .paths
+                        .iter()
+                        .filter(|path| !path.starts_with(thumb_dir.local_path()))
+                    {
+                        tracing::info!("clearing cache for {}, got fs update", path.display());
+                        if db.clear_cache_for(path).is_err() {
+                            tracing::error!("could not clear cache");
+                        }
                     }
                 }
-            }
-            _ => {}
-        })
-        .expect("could not create fs watcher");
+                _ => {}
+            })
+            .expect("could not create fs watcher");
 
-        watcher
-            .watch(Path::new(&file_dir), notify::RecursiveMode::Recursive)
-            .expect("could not watch file dir");
+            watcher
+                .watch(Path::new(&file_dir), notify::RecursiveMode::Recursive)
+                .expect("could not watch file dir");
 
-        loop {
-            std::thread::sleep(std::time::Duration::from_secs(config.cache_clear_interval));
-            db.clear_cache().expect("could not clear entire cache");
-        }
+            loop {
+                std::thread::sleep(std::time::Duration::from_secs(config.cache_clear_interval));
+                db.clear_cache().expect("could not clear entire cache");
+            }
+        };
+
+        // watch never returns, so getting here means it panicked
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(watch));
+        tracing::error!("fs watcher stopped, listings will no longer be cached");
+        db.watcher_healthy.store(false, Ordering::SeqCst);
     });
 
-    rouille::start_server(config.bind.clone(), move |request| {
-        let remote = request
-            .header("X-Real-IP")
-            .map(String::from)
-            .unwrap_or_else(|| request.remote_addr().to_string());
-        let full_url = request.url();
-        tracing::debug!("new request from {}: {}", remote, full_url);
-
-        if let Some(config_auth) = &config.auth {
-            if let Some(auth_value) = request.header("Authorization") {
-                let auth = auth_value.split(" ").collect::<Vec<_>>();
-                if auth.len() != 2 {
-                    tracing::warn!("broken auth header: {}", auth_value);
-                    return Page::bad_request(&config);
-                }
-                if auth[0] != "Basic" {
-                    tracing::warn!("broken auth type: {}", auth[0]);
-                    return Page::bad_request(&config);
+    rouille::start_server(
+        config.bind.clone(),
+        move |request| match std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            handle_request(request, db, &config)
+        })) {
+            Ok(response) => response,
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| String::from("<unknown panic>"));
+                tracing::error!(
+                    "panic while handling {} from {}: {}",
+                    request.raw_url(),
+                    config.client_ip(request),
+                    message
+                );
+
+                if config.show_panic_messages {
+                    Page::default()
+                        .with_title("internal server error")
+                        .with_paragraph(html_escape(&message))
+                        .with_code(500)
+                        .render(&config)
+                } else {
+                    Page::internal_error(&config)
                 }
-                use base64::Engine;
-                let Ok(bytes) = base64::engine::general_purpose::STANDARD.decode(&auth[1]) else {
-                    tracing::warn!("broken auth: {}", auth[1]);
-                    return Page::bad_request(&config);
+            }
+        },
+    );
+}
+
+fn handle_request(request: &rouille::Request, db: &Database, config: &Config) -> Response {
+    let remote = config.client_ip(request);
+    let full_url = request.url();
+    tracing::debug!("new request from {}: {}", remote, full_url);
+
+    if request.method() != "GET" && request.method() != "HEAD" {
+        tracing::debug!("rejecting {} from {}", request.method(), remote);
+        return Response::text("")
+            .with_status_code(405)
+            .with_unique_header("Allow", "GET, HEAD");
+    }
+
+    if let Some(config_auth) = &config.auth {
+        if let Some(auth_value) = request.header("Authorization") {
+            let auth = auth_value.split(" ").collect::<Vec<_>>();
+            if auth.len() != 2 {
+                tracing::warn!("broken auth header: {}", auth_value);
+                return Page::bad_request(config);
+            }
+            if auth[0] != "Basic" {
+                tracing::warn!("broken auth type: {}", auth[0]);
+                return Page::bad_request(config);
+            }
+            use base64::Engine;
+            let Ok(bytes) = base64::engine::general_purpose::STANDARD.decode(&auth[1]) else {
+                tracing::warn!("broken auth: {}", auth[1]);
+                return Page::bad_request(config);
+            };
+            let Ok(auth) = std::str::from_utf8(&bytes) else {
+                tracing::warn!("broken auth utf8: {}", auth[1]);
+                return Page::bad_request(config);
+            };
+            if auth != config_auth {
+                tracing::warn!("incorrect user/pass from {}: {}", remote, auth);
+                return Page::bad_request(config);
+            }
+        } else {
+            return Response::text(config.auth_message.as_deref().unwrap_or("need auth!"))
+                .with_status_code(401)
+                .with_unique_header(
+                    "WWW-Authenticate",
+                    format!(
+                        "Basic realm=\"{}\"",
+                        config
+                            .auth_realm
+                            .as_ref()
+                            .map(|s| s.as_str())
+                            .unwrap_or("dop")
+                    ),
+                );
+        }
+    }
+
+    if full_url == "/.dop/admin/export-index" {
+        let Some(admin_token) = config.admin_token.as_ref() else {
+            return Page::not_found(config, request);
+        };
+        let Some(token) = request.header("X-Admin-Token") else {
+            tracing::warn!("no admin token from {}", remote);
+            return Page::unauthorized(config);
+        };
+        if !constant_time_eq(token.as_bytes(), admin_token.as_bytes()) {
+            tracing::warn!("bad admin token from {}", remote);
+            return Page::forbidden(config);
+        }
+
+        tracing::info!("exporting index for {}", remote);
+        return Response::json(&db.export_index());
+    }
+
+    // these are answered on any path so the links work whatever page_root is
+    if request.get_param("favicon").is_some() {
+        let Some(favicon_path) = config.favicon_path.as_ref() else {
+            return Page::not_found(config, request);
+        };
+        let Ok(favicon) = FsFile::open(favicon_path) else {
+            tracing::error!("couldn't read favicon {}", favicon_path.display());
+            return Page::not_found(config, request);
+        };
+
+        let extension = favicon_path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        return Response::from_file(
+            match extension.as_deref() {
+                Some("svg") => "image/svg+xml",
+                Some("png") => "image/png",
+                _ => "image/x-icon",
+            },
+            favicon,
+        )
+        .with_unique_header("Cache-Control", config.cache_policy.clone());
+    }
+
+    if let Some(thumbnail) = request.get_param("thumbnail") {
+        let Ok(thumb) = db.open_thumbnail(&thumbnail) else {
+            tracing::error!("couldn't read thumbnail {}", thumbnail);
+            return Page::internal_error(config);
+        };
+        return Response::from_file("image/jpeg", thumb)
+            .with_unique_header("Cache-Control", "public, max-age=604800, immutable");
+    }
+
+    let url = if let Some(root) = config.page_root.as_ref() {
+        let redirect = match config.trailing_slash {
+            TrailingSlashMode::Add if full_url == *root => Some(format!("{}/", root)),
+            TrailingSlashMode::Remove
+                if full_url != *root && full_url.trim_end_matches('/') == root =>
+            {
+                Some(root.clone())
+            }
+            _ => None,
+        };
+        if let Some(location) = redirect {
+            let query = request.raw_query_string();
+            return Response::redirect_301(if query.is_empty() {
+                location
+            } else {
+                format!("{}?{}", location, query)
+            });
+        }
+
+        if !full_url.starts_with(root) {
+            tracing::debug!("url didn't start with page root");
+            return Page::bad_request(config);
+        }
+
+        &full_url
+    } else {
+        &full_url
+    };
+
+    let url_serve_path = ServePath::from(PathBuf::from(url));
+    let request_local_path = match LocalPath::from_serve_path(db, config, &url_serve_path) {
+        Ok(path) => path,
+        Err(e)
+            if e.downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) =>
+        {
+            return Page::not_found(config, request);
+        }
+        Err(e) => {
+            tracing::debug!("bad request path: {:#}", e);
+            return Page::bad_request(config);
+        }
+    };
+
+    tracing::debug!(
+        "path looks like {}",
+        request_local_path.local_path().display()
+    );
+
+    if request_local_path
+        .local_path()
+        .ancestors()
+        .all(|parent| parent != db.file_dir.local_path())
+        && request_local_path
+            .local_path()
+            .ancestors()
+            .all(|parent| parent != db.thumbnail_dir.local_path())
+    {
+        tracing::warn!(
+            "preventing directory traversal: {} tried to access {}",
+            remote,
+            request_local_path
+                .local_path()
+                .canonicalize()
+                .unwrap_or(PathBuf::from("(couldn't canonicalize)"))
+                .display()
+        );
+        return Page::bad_request(config);
+    }
+
+    tracing::debug!(
+        "serving {} on \"{}\"",
+        request_local_path.local_path().display(),
+        url
+    );
+
+    let no_cache = config.is_no_cache(
+        request_local_path
+            .local_path()
+            .strip_prefix(db.file_dir.local_path())
+            .unwrap_or(request_local_path.local_path()),
+    );
+
+    if request_local_path.local_path().is_dir() {
+        if let Some(_) = request.get_param("filelist") {
+            tracing::debug!("asked for file list");
+            let include_hidden = request
+                .get_param("include_hidden")
+                .is_none_or(|include| include != "false" && include != "0");
+            let file_list = db.file_list_in(config, &request_local_path, include_hidden);
+            return Response::json(&file_list);
+        }
+
+        if let Ok(maybe_content) = db.get_content_for(config, &url_serve_path) {
+            if let Some(content) = maybe_content {
+                let full_link = |path: &LocalPath| -> Result<String> {
+                    Ok(format!(
+                        "<a href='{}'>{}</a>",
+                        html_escape(&db.url_for(config, path)?),
+                        html_escape(&path.local_path().display().to_string()),
+                    ))
+                };
+                let filename_link = |path: &LocalPath| -> Result<String> {
+                    Ok(format!(
+                        "<a href='{}'>{}</a>",
+                        html_escape(&db.url_for(config, path)?),
+                        path.local_path()
+                            .file_name()
+                            .map(OsStr::to_string_lossy)
+                            .map(|s| html_escape(&s))
+                            .unwrap_or("???".into())
+                    ))
                 };
-                let Ok(auth) = std::str::from_utf8(&bytes) else {
-                    tracing::warn!("broken auth utf8: {}", auth[1]);
-                    return Page::bad_request(&config);
+                let ancestors = request_local_path
+                    .local_path()
+                    .ancestors()
+                    .take_while(|parent| *parent != db.file_dir.local_path().parent().unwrap())
+                    .collect::<Vec<_>>();
+                let Ok(title) = ancestors.into_iter().rev().skip(1).fold(
+                    full_link(&db.file_dir),
+                    |acc, parent| {
+                        acc.and_then(|acc| {
+                            let link = filename_link(&LocalPath::from(parent.to_path_buf()))?;
+                            Ok(acc + "/" + &link)
+                        })
+                    },
+                ) else {
+                    return Page::internal_error(config);
                 };
-                if auth != config_auth {
-                    tracing::warn!("incorrect user/pass from {}: {}", remote, auth);
-                    return Page::bad_request(&config);
+
+                // the cached listing is rebuilt whenever the watcher sees a change,
+                // so hashing it is enough to tell whether the browser's copy is current
+                let mut hasher = md5_rs::Context::new();
+                hasher.read(title.as_bytes());
+                hasher.read(content.as_bytes());
+                let etag = format!("\"{}\"", hex_digest(hasher));
+
+                let response = Page::default()
+                    .with_tab_title(html_escape(
+                        &request_local_path.local_path().display().to_string(),
+                    ))
+                    .with_title(title)
+                    .with_content(content)
+                    .render(config)
+                    .with_etag(request, etag);
+
+                if no_cache {
+                    response.with_unique_header("Cache-Control", Config::NO_CACHE_POLICY)
+                } else if let Some(dir_cache_policy) = config.dir_cache_policy.as_ref() {
+                    response.with_unique_header("Cache-Control", dir_cache_policy.clone())
+                } else {
+                    response
                 }
             } else {
-                return Response::text("need auth!")
-                    .with_status_code(401)
-                    .with_unique_header(
-                        "WWW-Authenticate",
-                        format!(
-                            "Basic realm=\"{}\"",
-                            config
-                                .auth_realm
-                                .as_ref()
-                                .map(|s| s.as_str())
-                                .unwrap_or("dop")
-                        ),
-                    );
+                Page::not_found(config, request)
             }
+        } else {
+            Page::internal_error(config)
         }
+    } else {
+        let Ok(file) = std::fs::File::open(request_local_path.local_path()) else {
+            return Page::not_found(config, request);
+        };
 
-        if Some(&full_url) == config.page_root.as_ref() {
-            if let Some(thumbnail) = request.get_param("thumbnail") {
-                let Ok(thumb) = db.open_thumbnail(&thumbnail) else {
-                    tracing::error!("couldn't read thumbnail {}", thumbnail);
-                    return Page::internal_error(&config);
-                };
-                return Response::from_file("image/jpeg", thumb)
-                    .with_unique_header("Cache-Control", "public, max-age=604800, immutable");
+        let extension = request_local_path
+            .local_path()
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+
+        Response::from_file(
+            match extension.as_ref().map(|s| s.as_str()) {
+                Some("jpg" | "jpeg") => "image/jpeg",
+                Some("png") => "image/png",
+                Some("tiff" | "tif") => "image/tiff",
+                Some("bmp") => "image/bmp",
+                Some("gif") => "image/gif",
+                Some("txt") => "text/plain",
+                Some("svg") => "image/svg+xml",
+                Some("pdf") => "application/pdf",
+                _ => "application/binary",
+            },
+            file,
+        )
+        .with_unique_header(
+            "Cache-Control",
+            if no_cache {
+                Config::NO_CACHE_POLICY
+            } else {
+                config.cache_policy_for(extension.as_deref())
             }
+            .to_string(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    /// Fresh directory under the system temp dir, removed again on drop
+    pub struct TempDir(pub PathBuf);
+
+    impl TempDir {
+        pub fn new(name: &str) -> TempDir {
+            let dir =
+                std::env::temp_dir().join(format!("dop-test-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir.canonicalize().unwrap())
         }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
 
-        let url = if let Some(root) = config.page_root.as_ref() {
-            if !full_url.starts_with(root) {
-                tracing::debug!("url didn't start with page root");
-                return Page::bad_request(&config);
+    /// Config with file and thumbnail dirs inside `tmp`, plus any extra toml
+    pub fn test_config(tmp: &TempDir, extra: &str) -> Config {
+        let config_path = tmp.0.join("config.toml");
+        std::fs::create_dir_all(tmp.0.join("files")).unwrap();
+        std::fs::write(
+            &config_path,
+            format!(
+                "bind = \"127.0.0.1:0\"\nfile_dir = \"files\"\nthumbnail_dir = \"thumbnails\"\n{}",
+                extra
+            ),
+        )
+        .unwrap();
+        std::fs::create_dir_all(tmp.0.join("thumbnails")).unwrap();
+        Config::read_from(config_path.to_str().unwrap(), &[]).unwrap()
+    }
+
+    #[test]
+    fn only_get_and_head_are_allowed() {
+        let tmp = TempDir::new("methods");
+        let config = test_config(&tmp, "");
+        std::fs::write(tmp.0.join("files").join("a.txt"), "a").unwrap();
+        let db = Database::read_config_and_make_dirs(&config).unwrap();
+
+        for url in ["/", "/a.txt", "/missing"] {
+            for method in ["POST", "PUT", "DELETE", "PATCH"] {
+                let request = rouille::Request::fake_http(method, url, vec![], vec![]);
+                let response = handle_request(&request, &db, &config);
+                assert_eq!(response.status_code, 405, "{} {}", method, url);
             }
+        }
+        for method in ["GET", "HEAD"] {
+            let request = rouille::Request::fake_http(method, "/a.txt", vec![], vec![]);
+            assert_eq!(handle_request(&request, &db, &config).status_code, 200);
+        }
+    }
 
-            &full_url
-        } else {
-            &full_url
-        };
+    #[test]
+    fn favicon_is_served_on_any_path() {
+        let tmp = TempDir::new("favicon");
+        std::fs::write(tmp.0.join("fav.png"), "png").unwrap();
+        for (extra, page) in [("", "/"), ("page_root = \"/files\"", "/files")] {
+            let config = test_config(&tmp, &format!("favicon_path = \"fav.png\"\n{}", extra));
+            assert_eq!(config.favicon_path, Some(tmp.0.join("fav.png")));
+            std::fs::create_dir_all(tmp.0.join("files").join("sub")).unwrap();
+            let db = Database::read_config_and_make_dirs(&config).unwrap();
+
+            let listing = handle_request(
+                &rouille::Request::fake_http("GET", page, vec![], vec![]),
+                &db,
+                &config,
+            );
+            let mut body = String::new();
+            listing
+                .data
+                .into_reader_and_size()
+                .0
+                .read_to_string(&mut body)
+                .unwrap();
+            assert!(
+                body.contains(&format!("href=\"{}?favicon\"", page)),
+                "{}",
+                body
+            );
 
-        let url_serve_path = ServePath::from(PathBuf::from(url));
-        let Ok(request_local_path) = LocalPath::from_serve_path(&db, &config, &url_serve_path)
-        else {
-            return Page::bad_request(&config);
+            for url in [
+                format!("{}?favicon", page),
+                String::from("/files/sub?favicon"),
+            ] {
+                let request = rouille::Request::fake_http("GET", url.clone(), vec![], vec![]);
+                let response = handle_request(&request, &db, &config);
+                assert_eq!(response.status_code, 200, "{}", url);
+            }
+        }
+    }
+
+    #[test]
+    fn thumbnails_are_served_only_from_the_thumbnail_dir() {
+        let tmp = TempDir::new("thumbnail-names");
+        let config = test_config(&tmp, "");
+        std::fs::write(tmp.0.join("thumbnails").join("abc_75.jpg"), "jpg").unwrap();
+        let db = Database::read_config_and_make_dirs(&config).unwrap();
+
+        let get = |url: &str| {
+            let request = rouille::Request::fake_http("GET", url, vec![], vec![]);
+            handle_request(&request, &db, &config).status_code
         };
+        assert_eq!(get("/?thumbnail=abc_75.jpg"), 200);
+        assert_eq!(get("/some/dir?thumbnail=abc_75.jpg"), 200);
+        assert_ne!(get("/?thumbnail=../config.toml"), 200);
+        assert_ne!(get("/?thumbnail=/etc/hostname"), 200);
+    }
 
-        tracing::debug!(
-            "path looks like {}",
-            request_local_path.local_path().display()
+    fn write_png(path: &Path, width: u32, height: u32) {
+        ImageBuffer::from_pixel(width, height, Rgb([10u8, 20, 30]))
+            .save(path)
+            .unwrap();
+    }
+
+    #[test]
+    fn height_thumbnails_use_density_descriptors() {
+        let tmp = TempDir::new("height-srcset");
+        let config = test_config(
+            &tmp,
+            "thumbnail_height = 50\nthumbnail_sizes = [50, 100, 150]",
         );
+        write_png(&tmp.0.join("files").join("a.png"), 4, 2);
+        let db = Database::read_config_and_make_dirs(&config).unwrap();
+
+        let page = db
+            .get_content_for(&config, &ServePath::from(PathBuf::from("/")))
+            .unwrap()
+            .unwrap();
+        assert!(page.contains("_50.jpg 1x, "), "{}", page);
+        assert!(page.contains("_100.jpg 2x, "), "{}", page);
+        assert!(page.contains("_150.jpg 3x'"), "{}", page);
+        assert!(!page.contains("sizes="), "{}", page);
+    }
 
-        if request_local_path
-            .local_path()
-            .ancestors()
-            .all(|parent| parent != db.file_dir.local_path())
-            && request_local_path
-                .local_path()
-                .ancestors()
-                .all(|parent| parent != db.thumbnail_dir.local_path())
-        {
-            tracing::warn!(
-                "preventing directory traversal: {} tried to access {}",
-                remote,
-                request_local_path
-                    .local_path()
-                    .canonicalize()
-                    .unwrap_or(PathBuf::from("(couldn't canonicalize)"))
-                    .display()
+    #[test]
+    fn thin_images_still_get_thumbnails() {
+        let tmp = TempDir::new("thin-thumbnails");
+        for (name, extra) in [
+            ("wide.png", "thumbnail_sizes = [75]"),
+            ("tall.png", "thumbnail_height = 75"),
+        ] {
+            let config = test_config(&tmp, extra);
+            let _ = std::fs::remove_dir_all(tmp.0.join("files"));
+            std::fs::create_dir(tmp.0.join("files")).unwrap();
+            match name {
+                "wide.png" => write_png(&tmp.0.join("files").join(name), 1000, 1),
+                _ => write_png(&tmp.0.join("files").join(name), 1, 1000),
+            }
+            let db = Database::read_config_and_make_dirs(&config).unwrap();
+
+            db.index_and_build_thumbnail_db(&config).unwrap();
+            for (_, thumbnail) in db.thumbnails.values().flatten() {
+                assert!(thumbnail.thumbnail_path().exists(), "{}", name);
+            }
+            assert_eq!(db.thumbnails.len(), 1);
+        }
+    }
+
+    #[test]
+    fn thumbnail_names_survive_renames() {
+        let tmp = TempDir::new("rename-thumbnails");
+        let config = test_config(&tmp, "");
+        write_png(&tmp.0.join("files").join("a.png"), 4, 2);
+        let names = |config: &Config| {
+            let db = Database::read_config_and_make_dirs(config).unwrap();
+            db.thumbnails.into_values().collect::<Vec<_>>()
+        };
+
+        let before = names(&config);
+        std::fs::create_dir(tmp.0.join("files").join("moved")).unwrap();
+        std::fs::rename(
+            tmp.0.join("files").join("a.png"),
+            tmp.0.join("files").join("moved").join("b.png"),
+        )
+        .unwrap();
+        assert_eq!(before, names(&config));
+    }
+
+    #[test]
+    fn thumbnail_names_are_unique() {
+        let tmp = TempDir::new("unique-thumbnails");
+        let thumbnail_dir = LocalPath::from(tmp.0.clone());
+        let mut names = HashSet::new();
+        for i in 0..10_000 {
+            let path = tmp.0.join(format!("{}.png", i));
+            std::fs::write(&path, i.to_string()).unwrap();
+            let hash = metadata_hash(&path).unwrap();
+            let thumbnail = thumbnail_path(&hash, 300, &thumbnail_dir);
+
+            let name = thumbnail
+                .thumbnail_path()
+                .file_name()
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string();
+            let (hash, size) = name.split_once('_').unwrap();
+            assert!(
+                !hash.is_empty()
+                    && hash
+                        .chars()
+                        .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c)),
+                "{}",
+                name
             );
-            return Page::bad_request(&config);
+            assert_eq!(size, "300.jpg");
+            assert!(names.insert(name.clone()), "{} repeated", name);
         }
+    }
 
-        tracing::debug!(
-            "serving {} on \"{}\"",
-            request_local_path.local_path().display(),
-            url
+    #[test]
+    fn old_thumbnails_are_migrated_at_startup_only() {
+        let tmp = TempDir::new("migrate-thumbnails");
+        let config = test_config(&tmp, "thumbnail_size = 150");
+        let image = tmp.0.join("files").join("a.png");
+        write_png(&image, 300, 200);
+        // a made-up height, so a thumbnail made again instead of renamed would show
+        let old_thumbnail = tmp
+            .0
+            .join("thumbnails")
+            .join(path_hash(&image))
+            .with_extension("jpg");
+        write_png(&old_thumbnail, 150, 7);
+
+        let db = Database::read_config_and_make_dirs(&config).unwrap();
+        check(&config, &db).unwrap();
+        assert!(old_thumbnail.exists());
+
+        db.index_and_build_thumbnail_db(&config).unwrap();
+        assert!(!old_thumbnail.exists());
+        let (size, thumbnail) = &db.thumbnails[&LocalPath::from(image)][0];
+        assert_eq!(*size, 150);
+        assert_eq!(
+            image::image_dimensions(thumbnail.thumbnail_path()).unwrap(),
+            (150, 7)
         );
+    }
 
-        if request_local_path.local_path().is_dir() {
-            if let Some(_) = request.get_param("filelist") {
-                tracing::debug!("asked for file list");
-                let file_list = db.file_list_in(&config, &request_local_path);
-                return Response::json(&file_list);
-            }
+    #[test]
+    fn file_dir_must_not_be_in_thumbnail_dir() {
+        let tmp = TempDir::new("nested-dirs");
+        std::fs::create_dir_all(tmp.0.join("a").join("b")).unwrap();
+        let config_path = tmp.0.join("config.toml");
+        let database = |file_dir: &str, thumbnail_dir: &str| {
+            std::fs::write(
+                &config_path,
+                format!(
+                    "bind = \"127.0.0.1:0\"\nfile_dir = \"{}\"\nthumbnail_dir = \"{}\"\n",
+                    file_dir, thumbnail_dir
+                ),
+            )
+            .unwrap();
+            let config = Config::read_from(config_path.to_str().unwrap(), &[]).unwrap();
+            Database::read_config_and_make_dirs(&config)
+        };
 
-            if let Ok(maybe_content) = db.get_content_for(&config, &url_serve_path) {
-                if let Some(content) = maybe_content {
-                    let full_link = |path: &LocalPath| -> Result<String> {
-                        Ok(format!(
-                            "<a href='{}'>{}</a>",
-                            ServePath::from_local_path(&db, &config, path)?.to_string(true),
-                            path.local_path().display(),
-                        ))
-                    };
-                    let filename_link = |path: &LocalPath| -> Result<String> {
-                        Ok(format!(
-                            "<a href='{}'>{}</a>",
-                            ServePath::from_local_path(&db, &config, path)?.to_string(true),
-                            path.local_path()
-                                .file_name()
-                                .map(OsStr::to_string_lossy)
-                                .map(|s| s.to_string())
-                                .unwrap_or("???".into())
-                        ))
-                    };
-                    let ancestors = request_local_path
-                        .local_path()
-                        .ancestors()
-                        .take_while(|parent| *parent != db.file_dir.local_path().parent().unwrap())
-                        .collect::<Vec<_>>();
-                    let Ok(title) = ancestors.into_iter().rev().skip(1).fold(
-                        full_link(&db.file_dir),
-                        |acc, parent| {
-                            acc.and_then(|acc| {
-                                let link = filename_link(&LocalPath::from(parent.to_path_buf()))?;
-                                Ok(acc + "/" + &link)
-                            })
-                        },
-                    ) else {
-                        return Page::internal_error(&config);
-                    };
+        assert!(database("a", "a").is_err());
+        assert!(database("a/b", "a").is_err());
+        assert!(database("a", "a/b").is_ok());
+    }
 
-                    Page::default()
-                        .with_tab_title(request_local_path.local_path().display())
-                        .with_title(title)
-                        .with_content(content)
-                        .render(&config)
-                } else {
-                    Page::not_found(&config)
+    #[test]
+    fn items_in_subdirs_counts_every_descendant() {
+        let tmp = TempDir::new("items-in-subdirs");
+        let config = test_config(&tmp, "");
+        let d1 = tmp.0.join("files").join("d1");
+        let d2 = d1.join("d2");
+        let d3 = d2.join("d3");
+        std::fs::create_dir_all(&d3).unwrap();
+        for file in [
+            tmp.0.join("files").join("x.txt"),
+            d1.join("y.txt"),
+            d1.join("y2.txt"),
+            d2.join("z.txt"),
+            d3.join("w.txt"),
+        ] {
+            std::fs::write(file, "").unwrap();
+        }
+        let db = Database::read_config_and_make_dirs(&config).unwrap();
+
+        let index = db.export_index();
+        let total = index.len()
+            + index
+                .iter()
+                .map(|entry| entry.items_in_subdirs)
+                .sum::<usize>();
+        assert_eq!(total, 8);
+
+        let d1 = index.iter().find(|entry| entry.part_name == "d1").unwrap();
+        assert_eq!(d1.items_in_subdirs, 6);
+        assert_eq!((d1.dir_count, d1.file_count), (Some(1), Some(2)));
+        let d2 = d1.child_items.iter().find(|entry| entry.is_dir).unwrap();
+        assert_eq!(d2.items_in_subdirs, 3);
+    }
+
+    #[test]
+    fn unsupported_images_get_no_thumbnails() {
+        let tmp = TempDir::new("unsupported-images");
+        let config = test_config(&tmp, "");
+        for name in ["a.avif", "b.AVIF", "c.heic", "d.HEIF"] {
+            std::fs::write(tmp.0.join("files").join(name), "not really").unwrap();
+        }
+        let db = Database::read_config_and_make_dirs(&config).unwrap();
+        assert!(db.thumbnails.is_empty());
+    }
+
+    #[test]
+    fn trailing_slash_redirects_keep_the_query() {
+        let tmp = TempDir::new("trailing-slash");
+        for (mode, url, location) in [
+            ("remove", "/files/", Some("/files")),
+            ("remove", "/files/?a=1&b=2", Some("/files?a=1&b=2")),
+            ("remove", "/files?a=1", None),
+            ("add", "/files", Some("/files/")),
+            ("add", "/files?a=1", Some("/files/?a=1")),
+            ("add", "/files/?a=1", None),
+            ("ignore", "/files", None),
+            ("ignore", "/files/", None),
+        ] {
+            let config = test_config(
+                &tmp,
+                &format!("page_root = \"/files\"\ntrailing_slash = \"{}\"", mode),
+            );
+            let db = Database::read_config_and_make_dirs(&config).unwrap();
+
+            let request = rouille::Request::fake_http("GET", url, vec![], vec![]);
+            let response = handle_request(&request, &db, &config);
+            let header = response
+                .headers
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case("Location"))
+                .map(|(_, value)| value.to_string());
+            match location {
+                Some(location) => {
+                    assert_eq!(response.status_code, 301, "{} {}", mode, url);
+                    assert_eq!(header.as_deref(), Some(location), "{} {}", mode, url);
+                }
+                None => {
+                    assert_eq!(response.status_code, 200, "{} {}", mode, url);
+                    assert_eq!(header, None, "{} {}", mode, url);
                 }
-            } else {
-                Page::internal_error(&config)
             }
-        } else {
-            let Ok(file) = std::fs::File::open(request_local_path.local_path()) else {
-                return Page::not_found(&config);
+        }
+    }
+
+    #[test]
+    fn root_links_skip_the_trailing_slash_redirect() {
+        let tmp = TempDir::new("root-links");
+        for (mode, root, redirected) in [
+            ("add", "/files/", "/files'"),
+            ("remove", "/files", "/files/'"),
+            ("ignore", "/files", "/files/'"),
+        ] {
+            let config = test_config(
+                &tmp,
+                &format!("page_root = \"/files\"\ntrailing_slash = \"{}\"", mode),
+            );
+            std::fs::create_dir_all(tmp.0.join("files").join("sub")).unwrap();
+            let db = Database::read_config_and_make_dirs(&config).unwrap();
+
+            let request = rouille::Request::fake_http("GET", "/files/sub", vec![], vec![]);
+            for response in [
+                handle_request(&request, &db, &config),
+                Page::not_found(&config, &request),
+            ] {
+                let mut body = String::new();
+                let (mut reader, _) = response.data.into_reader_and_size();
+                reader.read_to_string(&mut body).unwrap();
+                assert!(
+                    body.contains(&format!("href='{}'>", root)),
+                    "{} {}",
+                    mode,
+                    body
+                );
+                assert!(
+                    !body.contains(&format!("href='{}", redirected)),
+                    "{} {}",
+                    mode,
+                    body
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn listing_counts_are_current() {
+        let tmp = TempDir::new("listing-counts");
+        let config = test_config(&tmp, "");
+        let dir = tmp.0.join("files").join("dir");
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        std::fs::create_dir_all(dir.join("b")).unwrap();
+        for name in ["x", "y", "z"] {
+            std::fs::write(dir.join(name), "").unwrap();
+        }
+        std::fs::create_dir_all(tmp.0.join("files").join("one")).unwrap();
+        std::fs::write(tmp.0.join("files").join("one").join("x"), "").unwrap();
+        let db = Database::read_config_and_make_dirs(&config).unwrap();
+        std::fs::write(dir.join("made after indexing"), "").unwrap();
+        std::fs::create_dir_all(dir.join("also made after indexing")).unwrap();
+
+        let page = db
+            .get_content_for(&config, &ServePath::from(PathBuf::from("/")))
+            .unwrap()
+            .unwrap();
+        assert!(
+            page.contains(">dir</a> <span class=\"counts\">3 folders, 4 files</span>"),
+            "{}",
+            page
+        );
+        assert!(
+            page.contains(">one</a> <span class=\"counts\">0 folders, 1 file</span>"),
+            "{}",
+            page
+        );
+
+        let page = db
+            .get_content_for(&config, &ServePath::from(PathBuf::from("/dir")))
+            .unwrap()
+            .unwrap();
+        assert!(
+            page.contains(">a</a> <span class=\"counts\">0 folders, 0 files</span>"),
+            "{}",
+            page
+        );
+    }
+
+    #[test]
+    fn missing_paths_are_not_found() {
+        let tmp = TempDir::new("not-found");
+        let config = test_config(
+            &tmp,
+            "page_root = \"/files\"\nnot_found_message = \"nothing here\"",
+        );
+        std::fs::write(tmp.0.join("files").join("a.txt"), "a").unwrap();
+        let db = Database::read_config_and_make_dirs(&config).unwrap();
+        let get = |url: &str| {
+            let request = rouille::Request::fake_http("GET", url, vec![], vec![]);
+            let response = handle_request(&request, &db, &config);
+            let mut body = String::new();
+            response
+                .data
+                .into_reader_and_size()
+                .0
+                .read_to_string(&mut body)
+                .unwrap();
+            (response.status_code, body)
+        };
+
+        let (code, body) = get("/files/missing.txt");
+        assert_eq!(code, 404);
+        assert!(body.contains("nothing here"), "{}", body);
+        assert_eq!(get("/files/missing/a.txt").0, 404);
+
+        let (code, body) = get("/files/missing.txt?x=1&y=<b>");
+        assert_eq!(code, 404);
+        assert!(
+            body.contains("/files/missing.txt?x=1&amp;y=&lt;b&gt;"),
+            "{}",
+            body
+        );
+
+        assert_eq!(get("/files/a.txt").0, 200);
+        assert_eq!(get("/files/../config.toml").0, 400);
+        assert_eq!(get("/elsewhere/a.txt").0, 400);
+    }
+
+    #[test]
+    fn pages_are_not_cached_without_the_watcher() {
+        let tmp = TempDir::new("dead-watcher");
+        let config = test_config(&tmp, "");
+        std::fs::write(tmp.0.join("files").join("a.txt"), "").unwrap();
+        let db = Database::read_config_and_make_dirs(&config).unwrap();
+        let root = ServePath::from(PathBuf::from("/"));
+
+        db.get_content_for(&config, &root).unwrap();
+        std::fs::write(tmp.0.join("files").join("b.txt"), "").unwrap();
+        let page = db.get_content_for(&config, &root).unwrap().unwrap();
+        assert!(!page.contains("b.txt"), "{}", page);
+
+        db.watcher_healthy.store(false, Ordering::SeqCst);
+        let page = db.get_content_for(&config, &root).unwrap().unwrap();
+        assert!(page.contains("b.txt"), "{}", page);
+
+        let response = handle_request(
+            &rouille::Request::fake_http("GET", "/", vec![], vec![]),
+            &db,
+            &config,
+        );
+        assert_eq!(response.status_code, 200);
+        let response = handle_request(
+            &rouille::Request::fake_http("GET", "/?filelist", vec![], vec![]),
+            &db,
+            &config,
+        );
+        assert_eq!(response.status_code, 200);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn descriptions_stay_inside_the_file_dir() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = TempDir::new("description-symlinks");
+        std::fs::create_dir_all(tmp.0.join("files").join("out")).unwrap();
+        std::fs::write(tmp.0.join("secret.txt"), "TOP SECRET").unwrap();
+        std::fs::write(tmp.0.join("files").join("ok.txt"), "fine").unwrap();
+        std::fs::create_dir_all(tmp.0.join("files").join("in")).unwrap();
+        symlink(
+            tmp.0.join("secret.txt"),
+            tmp.0.join("files").join("out").join("README.txt"),
+        )
+        .unwrap();
+        symlink(
+            "../ok.txt",
+            tmp.0.join("files").join("in").join("README.txt"),
+        )
+        .unwrap();
+
+        for (follow, inside_shown) in [("false", false), ("true", true)] {
+            let config = test_config(&tmp, &format!("follow_symlinks = {}", follow));
+            let db = Database::read_config_and_make_dirs(&config).unwrap();
+            let listing = |dir: &str| {
+                db.get_content_for(&config, &ServePath::from(PathBuf::from(dir)))
+                    .unwrap()
+                    .unwrap()
             };
 
-            let extension = request_local_path
-                .local_path()
-                .extension()
-                .map(|ext| ext.to_string_lossy().to_lowercase());
-
-            Response::from_file(
-                match extension.as_ref().map(|s| s.as_str()) {
-                    Some("jpg" | "jpeg") => "image/jpeg",
-                    Some("png") => "image/png",
-                    Some("tiff" | "tif") => "image/tiff",
-                    Some("bmp") => "image/bmp",
-                    Some("gif") => "image/gif",
-                    Some("txt") => "text/plain",
-                    Some("svg") => "image/svg+xml",
-                    Some("pdf") => "application/pdf",
-                    _ => "application/binary",
-                },
-                file,
-            )
+            assert!(!listing("/out").contains("TOP SECRET"), "{}", follow);
+            assert_eq!(listing("/in").contains("fine"), inside_shown, "{}", follow);
         }
-    });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_list_comes_from_the_index() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = TempDir::new("file-list");
+        let files = tmp.0.join("files");
+        std::fs::create_dir_all(files.join("dir")).unwrap();
+        std::fs::create_dir_all(tmp.0.join("outside")).unwrap();
+        std::fs::write(tmp.0.join("outside").join("secret.txt"), "").unwrap();
+        std::fs::write(files.join("dir").join("a.txt"), "").unwrap();
+        std::fs::write(files.join(".hidden"), "").unwrap();
+        symlink(tmp.0.join("outside"), files.join("out")).unwrap();
+        symlink("..", files.join("dir").join("loop")).unwrap();
+
+        for follow in ["false", "true"] {
+            let config = test_config(&tmp, &format!("follow_symlinks = {}", follow));
+            let db = Database::read_config_and_make_dirs(&config).unwrap();
+            std::fs::write(files.join("dir").join("late.txt"), "").unwrap();
+
+            let list = |path: &Path, include_hidden| {
+                let mut list = db
+                    .file_list_in(
+                        &config,
+                        &LocalPath::from(path.canonicalize().unwrap()),
+                        include_hidden,
+                    )
+                    .into_iter()
+                    .map(|entry| entry.display_path)
+                    .collect::<Vec<_>>();
+                list.sort();
+                list
+            };
+            assert_eq!(
+                list(&files, true),
+                ["", ".hidden", "dir", "dir/a.txt"],
+                "{}",
+                follow
+            );
+            assert_eq!(list(&files, false), ["", "dir", "dir/a.txt"], "{}", follow);
+            assert_eq!(list(&files.join("dir"), true), ["dir", "dir/a.txt"]);
+            assert!(list(&tmp.0.join("outside"), true).is_empty());
+
+            std::fs::remove_file(files.join("dir").join("late.txt")).unwrap();
+        }
+    }
+
+    #[test]
+    fn listing_escapes_file_names() {
+        let tmp = TempDir::new("listing-escape");
+        let config = test_config(&tmp, "");
+        std::fs::write(tmp.0.join("files").join("<b>&'x.txt"), "").unwrap();
+        std::fs::create_dir(tmp.0.join("files").join("<i>dir")).unwrap();
+        let db = Database::read_config_and_make_dirs(&config).unwrap();
+
+        let page = db
+            .get_content_for(&config, &ServePath::from(PathBuf::from("/")))
+            .unwrap()
+            .unwrap();
+        assert!(page.contains(">&lt;b&gt;&amp;&#39;x.txt</a>"), "{}", page);
+        assert!(page.contains(">&lt;i&gt;dir</a>"), "{}", page);
+        assert!(!page.contains("<b>"));
+        assert!(!page.contains("<i>"));
+    }
+
+    #[test]
+    fn breadcrumbs_are_percent_encoded() {
+        let tmp = TempDir::new("breadcrumb-encoding");
+        let config = test_config(&tmp, "");
+        std::fs::create_dir_all(tmp.0.join("files").join("a b café").join("x")).unwrap();
+        let db = Database::read_config_and_make_dirs(&config).unwrap();
+
+        let request = rouille::Request::fake_http("GET", "/a%20b%20caf%C3%A9/x", vec![], vec![]);
+        let response = handle_request(&request, &db, &config);
+        assert_eq!(response.status_code, 200);
+        let mut body = String::new();
+        let (mut reader, _) = response.data.into_reader_and_size();
+        reader.read_to_string(&mut body).unwrap();
+        assert!(body.contains("href='/a%20b%20caf%C3%A9'>"), "{}", body);
+        assert!(body.contains("href='/a%20b%20caf%C3%A9/x'>"), "{}", body);
+    }
+
+    #[test]
+    fn page_root_is_normalized() {
+        for (page_root, expected) in [
+            ("", None),
+            ("/", None),
+            ("//", None),
+            ("files", Some("/files")),
+            ("/files/", Some("/files")),
+            ("/a/b", Some("/a/b")),
+            ("/.dopey", Some("/.dopey")),
+            ("/a/.dop", Some("/a/.dop")),
+        ] {
+            assert_eq!(
+                Config::parse_page_root(page_root, "test")
+                    .unwrap()
+                    .as_deref(),
+                expected,
+                "{:?}",
+                page_root
+            );
+        }
+    }
+
+    #[test]
+    fn pathological_page_roots_are_rejected() {
+        for page_root in [
+            "/a//b",
+            "/a/./b",
+            "/..",
+            "/a/../b",
+            "/a?b",
+            "/a#b",
+            "/a%20b",
+            "/.dop",
+            "/.dop/files",
+        ] {
+            assert!(
+                Config::parse_page_root(page_root, "test").is_err(),
+                "{:?}",
+                page_root
+            );
+        }
+    }
+
+    #[test]
+    fn relative_thumbnail_dir_is_relative_to_config() {
+        let tmp = TempDir::new("relative-thumbnail-dir");
+        std::fs::create_dir_all(tmp.0.join("conf").join("thumbs")).unwrap();
+        std::fs::create_dir_all(tmp.0.join("files")).unwrap();
+        let config_path = tmp.0.join("conf").join("config.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                "bind = \"127.0.0.1:0\"\nfile_dir = \"{}\"\nthumbnail_dir = \"thumbs\"\n",
+                tmp.0.join("files").display()
+            ),
+        )
+        .unwrap();
+
+        let config = Config::read_from(config_path.to_str().unwrap(), &[]).unwrap();
+        let db = Database::read_config_and_make_dirs(&config).unwrap();
+        assert_eq!(
+            db.thumbnail_dir.local_path(),
+            tmp.0.join("conf").join("thumbs")
+        );
+    }
+
+    #[test]
+    fn relative_file_dir_can_leave_the_config_dir() {
+        let tmp = TempDir::new("relative-file-dir");
+        std::fs::create_dir_all(tmp.0.join("etc").join("dop").join("thumbs")).unwrap();
+        std::fs::create_dir_all(tmp.0.join("srv").join("files")).unwrap();
+        let config_path = tmp.0.join("etc").join("dop").join("config.toml");
+        std::fs::write(
+            &config_path,
+            "bind = \"127.0.0.1:0\"\nfile_dir = \"../../srv/files\"\nthumbnail_dir = \"thumbs\"\n",
+        )
+        .unwrap();
+
+        let config = Config::read_from(config_path.to_str().unwrap(), &[]).unwrap();
+        let db = Database::read_config_and_make_dirs(&config).unwrap();
+        assert_eq!(db.file_dir.local_path(), tmp.0.join("srv").join("files"));
+    }
+
+    #[test]
+    fn template_has_every_config_key() {
+        // commented out settings are `#key`, explanations are `# words`
+        let template = Config::TEMPLATE
+            .lines()
+            .map(|line| match line.strip_prefix('#') {
+                Some(setting) if !setting.starts_with(' ') => setting,
+                _ => line,
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let toml = toml::from_str::<toml::Value>(&template).unwrap();
+        let in_template = toml.as_table().unwrap();
+
+        let (_, read_keys) = Config::from_toml(&toml, "template").unwrap();
+        for key in read_keys.iter() {
+            assert!(
+                in_template.contains_key(key),
+                "{} is missing from the template",
+                key
+            );
+        }
+        for key in in_template.keys() {
+            assert!(
+                read_keys.contains(key),
+                "{} in the template isn't read",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn cache_policies_are_validated() {
+        for policy in [
+            "private, max-age=3600, must-revalidate",
+            "no-cache",
+            "public,s-maxage=10",
+            "no-cache=\"Set-Cookie\"",
+        ] {
+            assert!(
+                Config::validate_cache_policy(policy).is_ok(),
+                "{:?}",
+                policy
+            );
+        }
+        for policy in [
+            "",
+            "private,,no-cache",
+            "max-age=",
+            "no cache",
+            "no-cache=\"Set-Cookie",
+            "no-cache\r\nX-Injected: 1",
+            "max-age=\"é\"",
+        ] {
+            assert!(
+                Config::validate_cache_policy(policy).is_err(),
+                "{:?}",
+                policy
+            );
+        }
+    }
+
+    #[test]
+    fn config_may_start_with_a_bom() {
+        let tmp = TempDir::new("bom");
+        let config_path = tmp.0.join("config.toml");
+        std::fs::write(&config_path, "\u{FEFF}bind = \"127.0.0.1:0\"\n").unwrap();
+
+        let toml = Config::read_toml(config_path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            toml.get("bind").and_then(toml::Value::as_str),
+            Some("127.0.0.1:0")
+        );
+    }
+
+    #[test]
+    fn overlays_merge_tables_and_replace_the_rest() {
+        let mut base = toml::from_str::<toml::Value>(
+            r#"
+            bind = "127.0.0.1:8888"
+            thumbnail_sizes = [75, 150]
+            [file_icons]
+            pdf = "a"
+            zip = "b"
+            "#,
+        )
+        .unwrap();
+        let overlay = toml::from_str::<toml::Value>(
+            r#"
+            bind = "0.0.0.0:80"
+            thumbnail_sizes = [300]
+            page_root = "/files"
+            [file_icons]
+            zip = "c"
+            "#,
+        )
+        .unwrap();
+
+        Config::merge_toml(&mut base, overlay);
+        let expected = toml::from_str::<toml::Value>(
+            r#"
+            bind = "0.0.0.0:80"
+            thumbnail_sizes = [300]
+            page_root = "/files"
+            [file_icons]
+            pdf = "a"
+            zip = "c"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(base, expected);
+    }
+
+    #[test]
+    fn env_vars_are_expanded_in_strings() {
+        std::env::set_var("DOP_TEST_EXPAND_HOME", "/home/dop");
+        std::env::set_var("DOP_TEST_EXPAND_EMPTY", "");
+        let expand = |value: &str| {
+            let mut value = toml::Value::String(value.to_string());
+            Config::expand_env(&mut value, "test").map(|_| value.as_str().unwrap().to_string())
+        };
+
+        assert_eq!(
+            expand("${DOP_TEST_EXPAND_HOME}/files").unwrap(),
+            "/home/dop/files"
+        );
+        assert_eq!(
+            expand("${DOP_TEST_EXPAND_HOME}${DOP_TEST_EXPAND_EMPTY}:${DOP_TEST_EXPAND_HOME}")
+                .unwrap(),
+            "/home/dop:/home/dop"
+        );
+        assert_eq!(
+            expand("$${DOP_TEST_EXPAND_HOME}").unwrap(),
+            "${DOP_TEST_EXPAND_HOME}"
+        );
+        assert_eq!(expand("costs $5, $HOME").unwrap(), "costs $5, $HOME");
+        assert!(expand("${DOP_TEST_EXPAND_UNSET}").is_err());
+        assert!(expand("${DOP_TEST_EXPAND_HOME").is_err());
+
+        let mut nested = toml::from_str::<toml::Value>(
+            "dirs = [\"${DOP_TEST_EXPAND_HOME}\"]\n[table]\nkey = \"${DOP_TEST_EXPAND_HOME}\"\nn = 1",
+        )
+        .unwrap();
+        Config::expand_env(&mut nested, "test").unwrap();
+        assert_eq!(nested["dirs"][0].as_str(), Some("/home/dop"));
+        assert_eq!(nested["table"]["key"].as_str(), Some("/home/dop"));
+    }
+
+    #[test]
+    fn markdown_drops_unsafe_links() {
+        let html = markdown_to_html(
+            "[a](javascript:alert(1)) [b](JavaScript:alert(2)) ![c](data:image/png;base64,AAAA) <vbscript:x>",
+        );
+        for scheme in ["javascript:", "data:", "vbscript:"] {
+            assert!(
+                !html.to_lowercase().contains(&format!("=\"{}", scheme)),
+                "{}",
+                html
+            );
+        }
+
+        let html = markdown_to_html(
+            "[a](https://example.com/x) [b](sub/page.md) [c](/abs?q=1:2) [d](#top)",
+        );
+        for href in ["https://example.com/x", "sub/page.md", "/abs?q=1:2", "#top"] {
+            assert!(html.contains(&format!("href=\"{}\"", href)), "{}", html);
+        }
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_value() {
+        assert!(constant_time_eq(b"hunter2", b"hunter2"));
+        assert!(!constant_time_eq(b"hunter2", b"hunter3"));
+        assert!(!constant_time_eq(b"hunter2", b"hunter"));
+        assert!(!constant_time_eq(b"", b"x"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_dir_visits_each_dir_once() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = TempDir::new("walk-cycles");
+        std::fs::create_dir(tmp.0.join("A")).unwrap();
+        std::fs::create_dir(tmp.0.join("B")).unwrap();
+        std::fs::write(tmp.0.join("B").join("f.txt"), "").unwrap();
+        symlink("../B", tmp.0.join("A").join("toB")).unwrap();
+        symlink("../A", tmp.0.join("B").join("toA")).unwrap();
+        symlink("../B", tmp.0.join("A").join("toB2")).unwrap();
+
+        let files = File::walk_dir(
+            &LocalPath::from(tmp.0.clone()),
+            0,
+            None,
+            true,
+            &mut HashSet::new(),
+            &mut 0,
+            &|_| true,
+        )
+        .unwrap();
+        assert_eq!(File::count(&files), (1, 2));
+    }
 }
@@ -21,31 +21,67 @@ impl LocalPath {
         &self.0
     }
 
+    pub fn is_hidden(&self) -> bool {
+        self.0
+            .file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with('.'))
+    }
+
     pub fn from_serve_path(
         db: &crate::Database,
         config: &crate::Config,
         ServePath(serve_path): &ServePath,
     ) -> Result<LocalPath> {
-        if let Some(page_root) = config.page_root.as_ref() {
-            let page_root = PathBuf::from(page_root);
-            let local_path =
-                db.file_dir
-                    .local_path()
-                    .join(serve_path.strip_prefix(&page_root).map_err(|_| {
-                        crate::af!(
-                            "LocalPath: couldn't strip prefix {} from {}",
-                            page_root.display(),
-                            serve_path.display()
-                        )
-                    })?);
-
-            Ok(LocalPath(local_path.canonicalize().map_err(|_| {
-                crate::af!("LocalPath: couldn't canonicalize {}", local_path.display())
-            })?))
-        } else {
-            Ok(LocalPath(db.file_dir.local_path().join(serve_path)))
+        let local_path = join_serve_path(
+            db.file_dir.local_path(),
+            config.page_root.as_deref(),
+            serve_path,
+        )?;
+
+        // keep the io error around so a missing file can be told apart
+        Ok(LocalPath(local_path.canonicalize().map_err(|e| {
+            anyhow::Error::new(e).context(format!(
+                "LocalPath: couldn't canonicalize {}",
+                local_path.display()
+            ))
+        })?))
+    }
+}
+
+/// Where a request path under page_root points in file_dir, without touching the disk
+fn join_serve_path(file_dir: &Path, page_root: Option<&str>, serve_path: &Path) -> Result<PathBuf> {
+    use std::path::Component;
+
+    let serve_path = if let Some(page_root) = page_root {
+        let page_root = PathBuf::from(page_root);
+        serve_path.strip_prefix(&page_root).map_err(|_| {
+            crate::af!(
+                "LocalPath: couldn't strip prefix {} from {}",
+                page_root.display(),
+                serve_path.display()
+            )
+        })?
+    } else {
+        serve_path
+    };
+
+    // only keep the normal parts, joining a root or drive prefix would
+    // replace file_dir entirely
+    let mut local_path = file_dir.to_path_buf();
+    for part in serve_path.components() {
+        match part {
+            Component::RootDir | Component::Prefix(_) | Component::CurDir => {}
+            Component::ParentDir => {
+                return Err(crate::af!(
+                    "LocalPath: directory traversal in {}",
+                    serve_path.display()
+                ))
+            }
+            Component::Normal(part) => local_path.push(part),
         }
     }
+
+    Ok(local_path)
 }
 
 impl ServePath {
@@ -117,3 +153,79 @@ impl From<PathBuf> for ThumbnailPath {
         Self(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn join(page_root: Option<&str>, serve_path: &str) -> Result<PathBuf> {
+        join_serve_path(Path::new("/srv/files"), page_root, Path::new(serve_path))
+    }
+
+    #[test]
+    fn normal_segments_are_joined() {
+        assert_eq!(join(None, "/").unwrap(), Path::new("/srv/files"));
+        assert_eq!(
+            join(None, "/a/b.txt").unwrap(),
+            Path::new("/srv/files/a/b.txt")
+        );
+        assert_eq!(
+            join(Some("/files"), "/files").unwrap(),
+            Path::new("/srv/files")
+        );
+        assert_eq!(
+            join(Some("/files"), "/files/a").unwrap(),
+            Path::new("/srv/files/a")
+        );
+        assert_eq!(
+            join(Some("/x/y"), "/x/y/a/").unwrap(),
+            Path::new("/srv/files/a")
+        );
+    }
+
+    #[test]
+    fn dot_and_empty_segments_are_skipped() {
+        assert_eq!(join(None, "/./a/./b").unwrap(), Path::new("/srv/files/a/b"));
+        assert_eq!(join(None, "//a//b//").unwrap(), Path::new("/srv/files/a/b"));
+        assert_eq!(
+            join(Some("/files"), "/files//a").unwrap(),
+            Path::new("/srv/files/a")
+        );
+    }
+
+    #[test]
+    fn parent_segments_are_rejected() {
+        assert!(join(None, "/..").is_err());
+        assert!(join(None, "/a/../b").is_err());
+        assert!(join(None, "/a/..").is_err());
+        assert!(join(Some("/files"), "/files/../etc").is_err());
+    }
+
+    #[test]
+    fn still_encoded_segments_are_names() {
+        // request paths are decoded before they get here, so anything left is literal
+        assert_eq!(
+            join(None, "/%2e%2e").unwrap(),
+            Path::new("/srv/files/%2e%2e")
+        );
+        assert_eq!(join(None, "/a%2Fb").unwrap(), Path::new("/srv/files/a%2Fb"));
+    }
+
+    #[test]
+    fn paths_outside_page_root_are_rejected() {
+        assert!(join(Some("/files"), "/").is_err());
+        assert!(join(Some("/files"), "/other/a").is_err());
+        assert!(join(Some("/files"), "/filesystem").is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn drive_prefixes_are_dropped() {
+        let file_dir = Path::new(r"C:\srv\files");
+        let join = |serve_path: &str| join_serve_path(file_dir, None, Path::new(serve_path));
+        assert_eq!(join(r"C:\Windows").unwrap(), file_dir.join("Windows"));
+        assert_eq!(join(r"\\?\D:\a").unwrap(), file_dir.join("a"));
+        assert_eq!(join(r"/a\b").unwrap(), file_dir.join("a").join("b"));
+        assert!(join(r"\a\..\b").is_err());
+    }
+}